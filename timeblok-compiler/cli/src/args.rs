@@ -5,17 +5,50 @@ use clap::builder::PossibleValue;
 pub enum OutputTypes {
     Ics,
     Csv,
+    Json,
+    Agenda,
+    Md,
+    Org,
+    Jcal,
+    Xcal,
 }
 
 impl ValueEnum for OutputTypes {
     fn value_variants<'a>() -> &'a [Self] {
-        &[Self::Ics, Self::Csv]
+        &[Self::Ics, Self::Csv, Self::Json, Self::Agenda, Self::Md, Self::Org, Self::Jcal, Self::Xcal]
     }
 
     fn to_possible_value(&self) -> Option<PossibleValue> {
         Some(match self {
             Self::Ics => PossibleValue::new("ics").help("internet calendar format"),
             Self::Csv => PossibleValue::new("csv").help("comma-separated values"),
+            Self::Json => PossibleValue::new("json").help("resolved records as JSON"),
+            Self::Agenda => PossibleValue::new("agenda").alias("txt").help("plain-text agenda, listed by day"),
+            Self::Md => PossibleValue::new("md").help("markdown agenda, listed by day"),
+            Self::Org => PossibleValue::new("org").help("Emacs Org-mode headings"),
+            Self::Jcal => PossibleValue::new("jcal").help("ics content as JSON (RFC 7265)"),
+            Self::Xcal => PossibleValue::new("xcal").alias("xcs").help("ics content as XML (RFC 6321)"),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum TimeTypes {
+    Zoned,
+    Floating,
+    Utc,
+}
+
+impl ValueEnum for TimeTypes {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::Zoned, Self::Floating, Self::Utc]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            Self::Zoned => PossibleValue::new("zoned").help("times with their time zone, when set with /tz"),
+            Self::Floating => PossibleValue::new("floating").help("times without a time zone"),
+            Self::Utc => PossibleValue::new("utc").help("times in UTC"),
         })
     }
 }
@@ -43,10 +76,27 @@ pub struct Args {
     #[arg(long, short)]
     pub new: bool,
     /// Specify format of the output
-    /// Currently only supports `ics` and `csv`
+    /// Currently supports `ics`, `csv`, `json`, `agenda`, `md`, `org`, `jcal` and `xcal`
     /// Will try to infer from the file extension if not specified
     #[arg(long, default_value=None)]
     pub format: Option<OutputTypes>,    
+    /// End events without an end time when the next event of the day starts
+    /// The last event of the day lasts for the given number of minutes
+    #[arg(long, value_name = "MINUTES", num_args = 0..=1, default_missing_value = "30")]
+    pub timeblock: Option<u64>,
+    /// Write one event per date for recurring events, instead of a recurrence rule
+    #[arg(long)]
+    pub expand_recurrences: bool,
+    /// Columns of the csv output, separated by commas, eg. `name,start,end,@location`
+    /// Defaults to every column
+    #[arg(long, value_name = "COLUMNS", value_delimiter = ',')]
+    pub columns: Option<Vec<String>>,
+    /// Character that separates the columns of the csv output
+    #[arg(long, value_name = "CHAR", default_value_t = ',')]
+    pub delimiter: char,
+    /// How times are written to ics, jCal and xCal files
+    #[arg(long, value_name = "TIMES", default_value = "zoned")]
+    pub times: TimeTypes,
 }
 
 pub fn parse() -> Args {
@@ -4,21 +4,31 @@ use directories::{BaseDirs, ProjectDirs};
 
 use std::fs;
 use std::io::Write;
+use std::process::exit;
 
-use timeblok::{tb_to_records, records_to_resolved, resolved_to_ical, ir::ExactDateTime, resolved_to_csv};
+use timeblok::diagnostic::{has_errors, Diagnostic};
+use timeblok::{tb_to_records, records_to_resolved_with_options, resolved_to_ical_with_times, resolved_to_jcal, resolved_to_xcal, IcsTimes, ir::ExactDateTime, resolved_to_csv_with_options, resolved_to_json, resolved_to_agenda, resolved_to_org, AgendaStyle, CsvOptions, ResolveOptions};
 
-use crate::args::{parse, Args, OutputTypes};
+use crate::args::{parse, Args, OutputTypes, TimeTypes};
 
 pub fn main() {
     let args = parse();
     match try_main(args) {
-        Ok(_) => {}
+        Ok(true) => {}
+        Ok(false) => exit(1),
         Err(e) => {
             eprintln!("Error: {}", e);
+            exit(1);
         }
     }
 }
 
+fn report(diagnostics: &[Diagnostic], source: &str) {
+    for diagnostic in diagnostics {
+        eprintln!("{}\n", diagnostic.render(source));
+    }
+}
+
 fn handle_infile(infile: Option<String>, new: bool) -> Result<String> {
     match infile {
         Some(s) => Ok(s),
@@ -45,19 +55,26 @@ fn handle_infile(infile: Option<String>, new: bool) -> Result<String> {
     }
 }
 
-fn try_main(args: Args) -> Result<()> {
+/// Returns whether compilation finished without errors
+fn try_main(args: Args) -> Result<bool> {
     let infile = handle_infile(args.infile, args.new)?;
     let metadata = fs::metadata(&infile)?;
     let created = metadata.created()?;
     let file = fs::read_to_string(&infile)?;
-    let records = tb_to_records(&file)?;
+    let (records, mut diagnostics) = tb_to_records(&file)?;
     if args.print {
         println!("{:#?}", records);
     }
     if args.parse_only {
-        return Ok(());
+        report(&diagnostics, &file);
+        return Ok(!has_errors(&diagnostics));
     }
-    let resolved = records_to_resolved(records, ExactDateTime::from_system_time(created))?;
+    let options = ResolveOptions {
+        time_blocking: args.timeblock,
+        expand_recurrences: args.expand_recurrences,
+    };
+    let (resolved, resolve_diagnostics) = records_to_resolved_with_options(records, ExactDateTime::from_system_time(created), &options)?;
+    diagnostics.extend(resolve_diagnostics);
     let ext = match &args.format{
         Some(s) => s.to_owned(),
         None => {
@@ -69,10 +86,57 @@ fn try_main(args: Args) -> Result<()> {
             }else{OutputTypes::Ics}
         }
     };
+    let times = match args.times {
+        TimeTypes::Zoned => IcsTimes::Zoned,
+        TimeTypes::Floating => IcsTimes::Floating,
+        TimeTypes::Utc => IcsTimes::Utc,
+    };
     let converted = match ext {
-        OutputTypes::Csv => resolved_to_csv(resolved)?,
-        OutputTypes::Ics => resolved_to_ical(resolved)?,
+        OutputTypes::Csv => {
+            if !args.delimiter.is_ascii() {
+                return Err(anyhow!("The csv delimiter must be an ASCII character, found {}", args.delimiter));
+            }
+            let options = CsvOptions {
+                columns: args.columns,
+                delimiter: args.delimiter as u8,
+            };
+            let (csv, convert_diagnostics) = resolved_to_csv_with_options(resolved, &options)?;
+            diagnostics.extend(convert_diagnostics);
+            csv
+        }
+        OutputTypes::Agenda | OutputTypes::Md => {
+            let style = if matches!(ext, OutputTypes::Md) { AgendaStyle::Markdown } else { AgendaStyle::Text };
+            let (agenda, convert_diagnostics) = resolved_to_agenda(resolved, style)?;
+            diagnostics.extend(convert_diagnostics);
+            agenda
+        }
+        OutputTypes::Org => {
+            let (org, convert_diagnostics) = resolved_to_org(resolved)?;
+            diagnostics.extend(convert_diagnostics);
+            org
+        }
+        OutputTypes::Json => {
+            let (json, convert_diagnostics) = resolved_to_json(resolved)?;
+            diagnostics.extend(convert_diagnostics);
+            json
+        }
+        OutputTypes::Jcal => {
+            let (jcal, convert_diagnostics) = resolved_to_jcal(resolved, times)?;
+            diagnostics.extend(convert_diagnostics);
+            jcal
+        }
+        OutputTypes::Xcal => {
+            let (xcal, convert_diagnostics) = resolved_to_xcal(resolved, times)?;
+            diagnostics.extend(convert_diagnostics);
+            xcal
+        }
+        OutputTypes::Ics => {
+            let (ical, convert_diagnostics) = resolved_to_ical_with_times(resolved, times)?;
+            diagnostics.extend(convert_diagnostics);
+            ical
+        }
     };
+    report(&diagnostics, &file);
     match &args.outfile {
         Some(path) => {
             let mut file = fs::File::create(path)?;
@@ -96,5 +160,5 @@ fn try_main(args: Args) -> Result<()> {
             }
         }
     }
-    Ok(())
+    Ok(!has_errors(&diagnostics))
 }
@@ -1,16 +1,44 @@
 
 
+use crate::diagnostic::Diagnostic;
+use crate::ir::recurrence::{Frequency, Recurrence, RecurrenceRule};
 use crate::ir::{
-    ExactDate, ExactDateTime, ExactEvent, ExactRange, ExactRecord, ExactTime, TimeZoneChoice,
+    ExactDate, ExactDateTime, ExactEvent, ExactRange, ExactRecord, ExactTime, Span, TimeZoneChoice, Todo,
 };
 use anyhow::{anyhow, Result};
 use chrono::LocalResult::{Single, self};
 use chrono::{prelude as cr, Datelike, NaiveDate, NaiveDateTime, NaiveTime, Timelike};
-use chrono::{Local, TimeZone, Utc};
+use chrono::{Duration, Local, Offset, TimeZone, Utc};
+use chrono_tz::{OffsetComponents, OffsetName, Tz};
 use icalendar as ical;
-use icalendar::{Component, EventLike};
+use icalendar::{CalendarDateTime, Component, DatePerhapsTime, EventLike};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
 use uuid::Uuid;
 
+const FLOATING_FORMAT: &str = "%Y%m%dT%H%M%S";
+
+/// How the times of events are written to ics files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IcsTimes {
+    /// Times in a named zone are written with its TZID, local times as floating times, and other times in UTC
+    #[default]
+    Zoned,
+    /// Wall-clock times without a zone, which calendar apps show in their own zone
+    Floating,
+    /// Every time in UTC
+    Utc,
+}
+
+// The form the times of a single event take
+#[derive(Clone, Copy)]
+enum Stamp {
+    Floating,
+    Zoned(Tz),
+    Utc,
+}
+
 impl ExactTime {
     pub fn to_chrono(self) -> Result<NaiveTime> {
         match NaiveTime::from_hms_opt(self.hour, self.minute, self.second) {
@@ -37,6 +65,13 @@ impl ExactDate {
             Some(res) => Ok(res),
         }
     }
+
+    pub fn succ(self) -> Result<Self> {
+        match self.to_chrono()?.succ_opt() {
+            Some(d) => Ok(Self::from_naive(d)),
+            None => Err(anyhow!("Invalid date: {} has no next day", self)),
+        }
+    }
 }
 
 impl ExactDateTime {
@@ -58,9 +93,40 @@ impl ExactDateTime {
                     _ => Err(anyhow!("Error processing DateTime: {}", baset)),
                 }
             }
+            // A time repeated when the clocks go back is taken the first time it occurs
+            TimeZoneChoice::Named(tz) => match tz.from_local_datetime(&baset) {
+                Single(t) | LocalResult::Ambiguous(t, _) => Ok(t.with_timezone(&Utc)),
+                LocalResult::None => Err(anyhow!("{} does not exist in {}, the clocks skip it", baset, tz)),
+            },
         }
     }
 
+    /// The wall-clock time, in whatever zone it is in.
+    pub fn naive(&self) -> Result<NaiveDateTime> {
+        Ok(NaiveDateTime::new(self.date.to_chrono()?, self.time.to_chrono()?))
+    }
+
+    fn to_ical_datetime(&self, stamp: Stamp) -> Result<CalendarDateTime> {
+        Ok(match stamp {
+            Stamp::Floating => CalendarDateTime::Floating(self.naive()?),
+            Stamp::Zoned(tz) => {
+                // Either end of a range may be in a zone of its own
+                let tz = match self.tz {
+                    TimeZoneChoice::Named(own) => own,
+                    _ => tz,
+                };
+                let instant = self.to_chrono()?;
+                let local = instant.with_timezone(&tz).naive_local();
+                // The second time through a repeated hour can only be told apart in UTC
+                if tz.from_local_datetime(&local).earliest().map(|t| t.with_timezone(&Utc)) != Some(instant) {
+                    return Ok(CalendarDateTime::Utc(instant));
+                }
+                CalendarDateTime::WithTimezone { date_time: local, tzid: tz.name().to_string() }
+            }
+            Stamp::Utc => CalendarDateTime::Utc(self.to_chrono()?),
+        })
+    }
+
     pub fn from_chrono(t: cr::DateTime<Utc>) -> Self {
         ExactDateTime {
             date: ExactDate {
@@ -77,6 +143,25 @@ impl ExactDateTime {
         }
     }
 
+    /// The time `t` in the zone `tz`, or in UTC if the wall-clock time would not lead back to `t`.
+    pub fn from_chrono_in(t: cr::DateTime<Utc>, tz: TimeZoneChoice) -> Self {
+        let local = match tz {
+            TimeZoneChoice::Local => t.with_timezone(&Local).naive_local(),
+            TimeZoneChoice::Utc => t.naive_utc(),
+            TimeZoneChoice::Offset(offset) => t.with_timezone(&offset).naive_local(),
+            TimeZoneChoice::Named(named) => t.with_timezone(&named).naive_local(),
+        };
+        let res = ExactDateTime {
+            date: ExactDate::from_naive(local.date()),
+            time: ExactTime::from_hms(local.hour(), local.minute(), local.second()),
+            tz,
+        };
+        match res.to_chrono() {
+            Ok(back) if back == t => res,
+            _ => Self::from_chrono(t),
+        }
+    }
+
     pub fn from_timestamp(timestamp: i64) -> Option<Self> {
         match Utc.timestamp_millis_opt(timestamp) {
             LocalResult::None => None,
@@ -89,7 +174,7 @@ impl ExactDateTime {
 }
 
 impl ExactEvent {
-    fn to_icalevent(&self, key: Option<String>, tsmp: Option<chrono::DateTime<Utc>>) -> Result<ical::Event> {
+    fn to_icalevent(&self, key: Option<String>, tsmp: Option<chrono::DateTime<Utc>>, times: IcsTimes) -> Result<ical::Event> {
         let mut calevent = ical::Event::new();
         calevent.summary(self.name.as_str());
         if let Some(notes) = self.notes.as_ref() {
@@ -104,23 +189,209 @@ impl ExactEvent {
         if let Some(tsmp)=tsmp{
             calevent.timestamp(tsmp);
         }
-        match &self.range {
+        let stamp = self.stamp(times);
+        let (start, end) = self.ical_range(stamp)?;
+        calevent = calevent.starts(start).ends(end).done();
+        if let Some(recurrence) = &self.recurrence {
+            let (until, exdates) = self.ical_recurrence(recurrence, stamp)?;
+            calevent.add_property("RRULE", &rrule(&recurrence.rule, until).to_ics());
+            // The exceptions share the form of DTSTART, so they go on one line, which importers keep whole
+            if let Some(first) = exdates.first() {
+                let values: Vec<String> = exdates.iter().map(|exdate| format_time(exdate, false)).collect();
+                let mut property = ical::Property::new("EXDATE", &values.join(","));
+                match first {
+                    DatePerhapsTime::Date(_) => {
+                        property.add_parameter("VALUE", "DATE");
+                    }
+                    DatePerhapsTime::DateTime(CalendarDateTime::WithTimezone { tzid, .. }) => {
+                        property.add_parameter("TZID", tzid);
+                    }
+                    DatePerhapsTime::DateTime(_) => {}
+                }
+                calevent.append_multi_property(property);
+            }
+        }
+        Ok(calevent)
+    }
+
+    /// The event as jCal and xCal write it, with the same properties as `to_icalevent`.
+    fn to_cal_component(&self, key: Option<String>, tsmp: Option<chrono::DateTime<Utc>>, times: IcsTimes) -> Result<CalComponent> {
+        let stamp = self.stamp(times);
+        let (start, end) = self.ical_range(stamp)?;
+        let uid = match key {
+            Some(s) => Uuid::new_v3(&Uuid::NAMESPACE_URL, s.as_bytes()),
+            None => Uuid::new_v4(),
+        };
+        let mut properties = vec![
+            CalProperty::time("dtstamp", tsmp.unwrap_or_else(Utc::now).into()),
+            CalProperty::text("uid", &uid.to_string()),
+            CalProperty::text("summary", &self.name),
+            CalProperty::time("dtstart", start),
+            CalProperty::time("dtend", end),
+        ];
+        if let Some(notes) = self.notes.as_ref() {
+            properties.push(CalProperty::text("description", &notes.description));
+            for prop in notes.properties.iter() {
+                properties.push(CalProperty::new(&prop.name, "unknown", CalValue::Text(prop.data.clone())));
+            }
+        }
+        if let Some(recurrence) = &self.recurrence {
+            let (until, exdates) = self.ical_recurrence(recurrence, stamp)?;
+            properties.push(CalProperty::new("rrule", "recur", rrule(&recurrence.rule, until)));
+            properties.extend(exdates.into_iter().map(|exdate| CalProperty::time("exdate", exdate)));
+        }
+        Ok(CalComponent { name: "vevent".to_string(), properties, components: vec![] })
+    }
+
+    // DTSTART and DTEND, which is exclusive for all-day events
+    fn ical_range(&self, stamp: Stamp) -> Result<(DatePerhapsTime, DatePerhapsTime)> {
+        Ok(match &self.range {
             ExactRange::TimeRange(range) => {
-                calevent = calevent
-                    .starts(range.start.to_chrono()?)
-                    .ends(range.end.to_chrono()?)
-                    .done();
+                let end = match stamp {
+                    // The end may be in another zone, so it is kept the same length after the start
+                    Stamp::Floating => {
+                        CalendarDateTime::Floating(range.start.naive()? + (range.end.to_chrono()? - range.start.to_chrono()?))
+                    }
+                    _ => range.end.to_ical_datetime(stamp)?,
+                };
+                (range.start.to_ical_datetime(stamp)?.into(), end.into())
             }
-            ExactRange::AllDay(date) => {
-                calevent = calevent.all_day(date.to_chrono()?).done();
+            ExactRange::AllDay(range) => (range.start.to_chrono()?.into(), range.end.succ()?.to_chrono()?.into()),
+        })
+    }
+
+    // UNTIL and the EXDATEs of a series, at the time of day it starts
+    fn ical_recurrence(&self, recurrence: &Recurrence, stamp: Stamp) -> Result<(DatePerhapsTime, Vec<DatePerhapsTime>)> {
+        let at = |date: ExactDate, stamp: Stamp| -> Result<DatePerhapsTime> {
+            Ok(match &self.range {
+                ExactRange::TimeRange(range) => ExactDateTime { date, ..range.start.clone() }.to_ical_datetime(stamp)?.into(),
+                ExactRange::AllDay(_) => date.to_chrono()?.into(),
+            })
+        };
+        // UNTIL is in UTC when DTSTART has a zone, and takes the same form as DTSTART otherwise
+        let until = match stamp {
+            Stamp::Zoned(_) => at(recurrence.until, Stamp::Utc)?,
+            _ => at(recurrence.until, stamp)?,
+        };
+        let exdates = recurrence.exdates.iter().map(|&date| at(date, stamp)).collect::<Result<_>>()?;
+        Ok((until, exdates))
+    }
+
+    fn stamp(&self, times: IcsTimes) -> Stamp {
+        let tz = match &self.range {
+            ExactRange::TimeRange(range) => range.start.tz,
+            ExactRange::AllDay(_) => return Stamp::Utc,
+        };
+        match (times, tz) {
+            (IcsTimes::Floating, _) => Stamp::Floating,
+            (IcsTimes::Utc, _) => Stamp::Utc,
+            (IcsTimes::Zoned, TimeZoneChoice::Named(tz)) => Stamp::Zoned(tz),
+            // Local times keep their wall-clock time, across daylight saving changes as well
+            (IcsTimes::Zoned, TimeZoneChoice::Local) => Stamp::Floating,
+            (IcsTimes::Zoned, _) => Stamp::Utc,
+        }
+    }
+
+    // Whether the event moves against UTC when the clocks change
+    fn follows_dst(&self) -> bool {
+        matches!(&self.range, ExactRange::TimeRange(range)
+            if matches!(range.start.tz, TimeZoneChoice::Local | TimeZoneChoice::Named(_)))
+    }
+}
+
+impl Todo {
+    /// The todo as jCal and xCal write it, with the same properties as `Todo::to_ical`.
+    fn to_cal_component(&self, key: Option<String>, tsmp: Option<chrono::DateTime<Utc>>) -> Result<CalComponent> {
+        let mut properties = vec![
+            CalProperty::time("dtstamp", tsmp.unwrap_or_else(Utc::now).into()),
+            CalProperty::text("uid", &key.unwrap_or_else(|| Uuid::new_v4().to_string())),
+            CalProperty::text("summary", &self.name),
+        ];
+        if let Some(due) = self.due {
+            properties.push(CalProperty::time("due", due.to_chrono()?.into()));
+        }
+        properties.push(CalProperty::text("status", ical::Property::from(self.status).value()));
+        Ok(CalComponent { name: "vtodo".to_string(), properties, components: vec![] })
+    }
+}
+
+fn weekday_code(weekday: &chrono::Weekday) -> String {
+    weekday.to_string()[..2].to_uppercase()
+}
+
+// The recurrence rule of a series, with its parts in the order ics files list them
+fn rrule(rule: &RecurrenceRule, until: DatePerhapsTime) -> CalValue {
+    let integers = |values: &[i64]| values.iter().map(|&n| CalValue::Integer(n)).collect();
+    let freq = match rule.freq {
+        Frequency::Daily => "DAILY",
+        Frequency::Weekly => "WEEKLY",
+        Frequency::Monthly => "MONTHLY",
+    };
+    let mut parts = vec![("freq", vec![CalValue::Text(freq.to_string())])];
+    if rule.interval > 1 {
+        parts.push(("interval", vec![CalValue::Integer(rule.interval)]));
+    }
+    if !rule.by_month.is_empty() {
+        parts.push(("bymonth", integers(&rule.by_month)));
+    }
+    if !rule.by_month_day.is_empty() {
+        parts.push(("bymonthday", integers(&rule.by_month_day)));
+    }
+    if !rule.by_day.is_empty() {
+        let days = rule.by_day.iter().map(|(n, w)| match n {
+            0 => CalValue::Text(weekday_code(w)),
+            n => CalValue::Text(format!("{}{}", n, weekday_code(w))),
+        });
+        parts.push(("byday", days.collect()));
+    }
+    parts.push(("until", vec![CalValue::Time(until)]));
+    CalValue::Recur(parts)
+}
+
+// A series that follows daylight saving would be an hour off for part of the year in UTC, so it is written date by date
+fn expand_for(records: Vec<ExactRecord>, times: IcsTimes) -> Vec<ExactRecord> {
+    match times {
+        IcsTimes::Utc => records
+            .into_iter()
+            .flat_map(|record| match record {
+                ExactRecord::Event(event) if event.recurrence.is_some() && event.follows_dst() => event
+                    .occurrences()
+                    .into_iter()
+                    .map(|range| ExactRecord::Event(ExactEvent { range, recurrence: None, ..event.clone() }))
+                    .collect(),
+                record => vec![record],
+            })
+            .collect(),
+        _ => records,
+    }
+}
+
+// The zones events are written in, with the first and last year each is used in
+fn used_zones(records: &[ExactRecord], times: IcsTimes) -> Vec<(Tz, i32, i32)> {
+    let mut zones: BTreeMap<&str, (Tz, i32, i32)> = BTreeMap::new();
+    for record in records {
+        if let ExactRecord::Event(event) = record {
+            if let (Stamp::Zoned(tz), ExactRange::TimeRange(range)) = (event.stamp(times), &event.range) {
+                let last = event.recurrence.as_ref().map_or(range.end.date, |r| r.until).year.max(range.end.date.year);
+                let end_tz = match range.end.tz {
+                    TimeZoneChoice::Named(end_tz) => end_tz,
+                    _ => tz,
+                };
+                for tz in [tz, end_tz] {
+                    let years = zones.entry(tz.name()).or_insert((tz, range.start.date.year, last));
+                    years.1 = years.1.min(range.start.date.year);
+                    years.2 = years.2.max(last);
+                }
             }
         }
-        Ok(calevent)
     }
+    zones.into_values().collect()
 }
 
-pub fn to_ical(records: Vec<ExactRecord>, deterministic_tsmp: Option<chrono::DateTime<Utc>>) -> String {
+pub fn to_ical(records: Vec<ExactRecord>, deterministic_tsmp: Option<chrono::DateTime<Utc>>, times: IcsTimes) -> (String, Vec<Diagnostic>) {
+    let records = expand_for(records, times);
     let mut calendar = ical::Calendar::new();
+    let mut diagnostics = vec![];
     for (i,record) in records.iter().enumerate() {
         let key = if deterministic_tsmp.is_some() {
             Some(i.to_string())
@@ -130,41 +401,917 @@ pub fn to_ical(records: Vec<ExactRecord>, deterministic_tsmp: Option<chrono::Dat
         
         match record {
             ExactRecord::Event(event) => {
-                match event.to_icalevent(key, deterministic_tsmp) {
+                let calevent = event.to_icalevent(key, deterministic_tsmp, times).map_err(|e| event.wrap_err(e));
+                match calevent {
                     Ok(calevent) => {
                         calendar.push(calevent);
                     }
                     Err(e) => {
-                        eprintln!("Error processing event: {}", e);
+                        diagnostics.push(Diagnostic::from_error("convert-event", &e));
                     }
                 }
             }
-            ExactRecord::Note(_) => {}
+            ExactRecord::Note(..) => {}
             ExactRecord::Todo(t) => {
-                match t.to_ical(key, deterministic_tsmp) {
+                let caltodo = t.to_ical(key, deterministic_tsmp).map_err(|e| t.wrap_err(e));
+                match caltodo {
                     Ok(caltodo) => {
                         calendar.push(caltodo);
                     }
                     Err(e) => {
-                        eprintln!("Error processing todo: {}", e);
+                        diagnostics.push(Diagnostic::from_error("convert-todo", &e));
                     }
                 }
             }
         }
     }
     calendar = calendar.done();
-    calendar.to_string()
+    let zones: Vec<IcsComponent> = used_zones(&records, times)
+        .into_iter()
+        .map(|(tz, first, last)| vtimezone(tz, first, last).to_ics())
+        .collect();
+    (write_ics(&calendar, &zones).expect("writing to a String can't fail"), diagnostics)
 }
 
-pub fn to_csv(records: Vec<ExactRecord>) -> Result<String> {
-    let mut wtr = csv::Writer::from_writer(vec![]);
-    wtr.write_record(["timerange", "event"])?;
-    for (_i,record) in records.iter().enumerate() {
-        if let ExactRecord::Event(event) = record {
-            wtr.write_record([event.range.to_string().as_str(), event.name.as_str()])?;
+/// Writes the calendar with the time zones before its other components.
+fn write_ics(calendar: &ical::Calendar, zones: &[IcsComponent]) -> Result<String, fmt::Error> {
+    let mut out = String::from("BEGIN:VCALENDAR\r\n");
+    for property in &calendar.properties {
+        out += &TryInto::<String>::try_into(property.clone())?;
+    }
+    for zone in zones {
+        write_component(zone, &mut out)?;
+    }
+    for component in &calendar.components {
+        match component {
+            ical::CalendarComponent::Event(event) => event.fmt_write(&mut out)?,
+            ical::CalendarComponent::Todo(todo) => todo.fmt_write(&mut out)?,
+            _ => {}
+        }
+    }
+    out += "END:VCALENDAR\r\n";
+    Ok(out)
+}
+
+// icalendar only has components for events, todos and venues, and would give a VTIMEZONE and its
+// observances the DTSTAMP and UID they can't have, so these are written here
+type IcsComponent = ical::parser::Component<'static>;
+
+fn write_component(component: &IcsComponent, out: &mut String) -> fmt::Result {
+    writeln!(out, "BEGIN:{}\r", component.name.as_str())?;
+    for property in &component.properties {
+        write!(out, "{}", property)?;
+    }
+    for child in &component.components {
+        write_component(child, out)?;
+    }
+    writeln!(out, "END:{}\r", component.name.as_str())
+}
+
+// The most years of offset changes a VTIMEZONE lists, later years follow its last yearly rules
+const MAX_ZONE_YEARS: i32 = 50;
+
+/// A STANDARD or DAYLIGHT observance of a VTIMEZONE
+#[derive(Clone)]
+struct Observance {
+    /// The wall-clock time just before the change
+    start: NaiveDateTime,
+    from: i32,
+    to: i32,
+    daylight: bool,
+    name: String,
+    /// The change repeats every year on the same weekday of the month
+    yearly: bool,
+    /// The last time a yearly change happens
+    until: Option<cr::DateTime<Utc>>,
+}
+
+impl Observance {
+    // The month, nth weekday (-1 for the last) and time of the change, which repeat if the zone keeps its rules
+    fn rule(&self) -> (u32, i64, cr::Weekday, NaiveTime, i32, i32) {
+        let date = self.start.date();
+        let nth = if (date + Duration::days(7)).month() != date.month() { -1 } else { (date.day() as i64 - 1) / 7 + 1 };
+        (date.month(), nth, date.weekday(), self.start.time(), self.from, self.to)
+    }
+
+    fn rrule(&self) -> Option<CalValue> {
+        if !self.yearly {
+            return None;
+        }
+        let (month, nth, weekday, ..) = self.rule();
+        let mut parts = vec![
+            ("freq", vec![CalValue::Text("YEARLY".to_string())]),
+            ("bymonth", vec![CalValue::Integer(month as i64)]),
+            ("byday", vec![CalValue::Text(format!("{}{}", nth, weekday_code(&weekday)))]),
+        ];
+        if let Some(until) = self.until {
+            parts.push(("until", vec![CalValue::Time(until.into())]));
+        }
+        Some(CalValue::Recur(parts))
+    }
+}
+/// The offsets of `tz` from the start of the year `first` to the end of `last`, with years that change
+/// the same way as the one before as yearly rules.
+fn observances(tz: Tz, first: i32, last: i32) -> Vec<Observance> {
+    let last = last.min(first + MAX_ZONE_YEARS);
+    let (mut day, end) = match (Utc.with_ymd_and_hms(first, 1, 1, 0, 0, 0), Utc.with_ymd_and_hms(last + 1, 1, 1, 0, 0, 0)) {
+        (Single(start), Single(end)) => (start, end),
+        _ => return vec![],
+    };
+    let offset_at = |t: cr::DateTime<Utc>| tz.offset_from_utc_datetime(&t.naive_utc());
+    let observance = |at: cr::DateTime<Utc>, from: cr::FixedOffset| {
+        let to = offset_at(at);
+        Observance {
+            start: at.naive_utc() + from,
+            from: from.local_minus_utc(),
+            to: to.fix().local_minus_utc(),
+            daylight: !to.dst_offset().is_zero(),
+            name: to.abbreviation().to_string(),
+            yearly: false,
+            until: None,
+        }
+    };
+    // The offset the zone starts the first year in
+    let mut res = vec![Observance { start: day.naive_utc(), ..observance(day, offset_at(day).fix()) }];
+    let mut years: Vec<Vec<Observance>> = vec![vec![]; (last - first + 1) as usize];
+    while day < end {
+        let next = day + Duration::days(1);
+        let before = offset_at(day).fix();
+        if offset_at(next).fix() != before {
+            // Narrow down to the first second of the new offset
+            let (mut lo, mut hi) = (day, next);
+            while hi - lo > Duration::seconds(1) {
+                let mid = lo + (hi - lo) / 2;
+                if offset_at(mid).fix() == before {
+                    lo = mid;
+                } else {
+                    hi = mid;
+                }
+            }
+            let change = observance(hi, before);
+            let year = (change.start.year() - first).clamp(0, last - first) as usize;
+            years[year].push(change);
+        }
+        day = next;
+    }
+    let mut i = 0;
+    while i < years.len() {
+        let rules: Vec<_> = years[i].iter().map(Observance::rule).collect();
+        let mut j = i + 1;
+        while j < years.len() && !rules.is_empty() && years[j].iter().map(Observance::rule).eq(rules.iter().cloned()) {
+            j += 1;
+        }
+        if j - i == 1 {
+            res.append(&mut years[i]);
+        } else {
+            // A run that lasts to the last year keeps going, as the zone's current rules do
+            for (k, change) in years[i].iter().enumerate() {
+                let until = (j < years.len()).then(|| {
+                    let last_change = &years[j - 1][k];
+                    Utc.from_utc_datetime(&(last_change.start - Duration::seconds(last_change.from as i64)))
+                });
+                res.push(Observance { yearly: true, until, ..change.clone() });
+            }
+        }
+        i = j;
+    }
+    res
+}
+
+/// A VTIMEZONE with the offsets of `tz` from the start of the year `first` to the end of `last`.
+fn vtimezone(tz: Tz, first: i32, last: i32) -> CalComponent {
+    let components = observances(tz, first, last)
+        .into_iter()
+        .map(|observance| {
+            let mut properties = vec![
+                CalProperty::time("dtstart", observance.start.into()),
+                CalProperty::new("tzoffsetfrom", "utc-offset", CalValue::UtcOffset(observance.from)),
+                CalProperty::new("tzoffsetto", "utc-offset", CalValue::UtcOffset(observance.to)),
+                CalProperty::text("tzname", &observance.name),
+            ];
+            if let Some(rule) = observance.rrule() {
+                properties.push(CalProperty::new("rrule", "recur", rule));
+            }
+            let name = if observance.daylight { "daylight" } else { "standard" };
+            CalComponent { name: name.to_string(), properties, components: vec![] }
+        })
+        .collect();
+    CalComponent { name: "vtimezone".to_string(), properties: vec![CalProperty::text("tzid", tz.name())], components }
+}
+
+/// A property value, which ics, jCal and xCal each write in their own way
+enum CalValue {
+    Text(String),
+    Integer(i64),
+    /// Seconds east of UTC
+    UtcOffset(i32),
+    Time(DatePerhapsTime),
+    Recur(Vec<(&'static str, Vec<CalValue>)>),
+}
+
+struct CalProperty {
+    name: String,
+    params: Vec<(String, String)>,
+    kind: &'static str,
+    values: Vec<CalValue>,
+}
+
+struct CalComponent {
+    name: String,
+    properties: Vec<CalProperty>,
+    components: Vec<CalComponent>,
+}
+
+// The order parts of a recurrence rule take in xCal
+const RECUR_PARTS: &[&str] = &[
+    "freq", "until", "count", "interval", "bysecond", "byminute", "byhour", "byday", "bymonthday", "byyearday",
+    "byweekno", "bymonth", "bysetpos", "wkst",
+];
+
+// `-0500`, or `-05:00` with a separator
+fn format_offset(seconds: i32, separator: &str) -> String {
+    let sign = if seconds < 0 { '-' } else { '+' };
+    format!("{}{:02}{}{:02}", sign, seconds.abs() / 3600, separator, seconds.abs() % 3600 / 60)
+}
+
+// `20230310T090000Z` as ics writes it, or `2023-03-10T09:00:00Z` when `extended` as jCal and xCal do
+fn format_time(value: &DatePerhapsTime, extended: bool) -> String {
+    let (date, time) = if extended { ("%Y-%m-%d", "%Y-%m-%dT%H:%M:%S") } else { ("%Y%m%d", FLOATING_FORMAT) };
+    match value {
+        DatePerhapsTime::Date(d) => d.format(date).to_string(),
+        DatePerhapsTime::DateTime(CalendarDateTime::Floating(t) | CalendarDateTime::WithTimezone { date_time: t, .. }) => {
+            t.format(time).to_string()
+        }
+        DatePerhapsTime::DateTime(CalendarDateTime::Utc(t)) => format!("{}Z", t.format(time)),
+    }
+}
+
+/// The calendar `to_ical` writes, as a tree of components for jCal and xCal.
+fn to_cal_tree(records: Vec<ExactRecord>, deterministic_tsmp: Option<chrono::DateTime<Utc>>, times: IcsTimes) -> (CalComponent, Vec<Diagnostic>) {
+    let records = expand_for(records, times);
+    let mut diagnostics = vec![];
+    let mut components: Vec<CalComponent> = used_zones(&records, times)
+        .into_iter()
+        .map(|(tz, first, last)| vtimezone(tz, first, last))
+        .collect();
+    for (i, record) in records.iter().enumerate() {
+        let key = deterministic_tsmp.map(|_| i.to_string());
+        match record {
+            ExactRecord::Event(event) => match event.to_cal_component(key, deterministic_tsmp, times) {
+                Ok(component) => components.push(component),
+                Err(e) => diagnostics.push(Diagnostic::from_error("convert-event", &event.wrap_err(e))),
+            },
+            ExactRecord::Todo(todo) => match todo.to_cal_component(key, deterministic_tsmp) {
+                Ok(component) => components.push(component),
+                Err(e) => diagnostics.push(Diagnostic::from_error("convert-todo", &todo.wrap_err(e))),
+            },
+            ExactRecord::Note(..) => {}
+        }
+    }
+    let properties = ical::Calendar::new().properties.iter().map(|p| CalProperty::text(p.key(), p.value())).collect();
+    (CalComponent { name: "vcalendar".to_string(), properties, components }, diagnostics)
+}
+
+impl CalValue {
+    fn to_ics(&self) -> String {
+        match self {
+            CalValue::Text(text) => text.replace('\\', "\\\\").replace(';', "\\;").replace(',', "\\,").replace('\n', "\\n"),
+            CalValue::Integer(n) => n.to_string(),
+            CalValue::UtcOffset(seconds) => format_offset(*seconds, ""),
+            CalValue::Time(t) => format_time(t, false),
+            CalValue::Recur(parts) => parts
+                .iter()
+                .map(|(key, values)| {
+                    let values: Vec<String> = values.iter().map(CalValue::to_ics).collect();
+                    format!("{}={}", key.to_uppercase(), values.join(","))
+                })
+                .collect::<Vec<_>>()
+                .join(";"),
+        }
+    }
+
+    fn to_jcal(&self) -> serde_json::Value {
+        match self {
+            CalValue::Text(text) => text.clone().into(),
+            CalValue::Integer(n) => (*n).into(),
+            CalValue::UtcOffset(seconds) => format_offset(*seconds, ":").into(),
+            CalValue::Time(t) => format_time(t, true).into(),
+            CalValue::Recur(parts) => {
+                let mut rule = serde_json::Map::new();
+                for (key, values) in parts {
+                    let value = match values.as_slice() {
+                        [value] => value.to_jcal(),
+                        values => values.iter().map(CalValue::to_jcal).collect(),
+                    };
+                    rule.insert(key.to_string(), value);
+                }
+                rule.into()
+            }
+        }
+    }
+
+    fn to_xcal(&self, kind: &str) -> String {
+        match self {
+            CalValue::Text(text) => format!("<{kind}>{}</{kind}>", xml_escape(text)),
+            CalValue::Integer(n) => format!("<{kind}>{}</{kind}>", n),
+            CalValue::UtcOffset(seconds) => format!("<{kind}>{}</{kind}>", format_offset(*seconds, ":")),
+            CalValue::Time(t) => format!("<{kind}>{}</{kind}>", format_time(t, true)),
+            CalValue::Recur(parts) => {
+                let mut parts: Vec<_> = parts.iter().collect();
+                parts.sort_by_key(|(key, _)| RECUR_PARTS.iter().position(|p| p == key));
+                let parts: String = parts
+                    .iter()
+                    .flat_map(|(key, values)| values.iter().map(move |v| v.to_xcal(key)))
+                    .collect();
+                format!("<{kind}>{}</{kind}>", parts)
+            }
+        }
+    }
+}
+
+impl CalProperty {
+    fn new(name: &str, kind: &'static str, value: CalValue) -> Self {
+        CalProperty { name: name.to_lowercase(), params: vec![], kind, values: vec![value] }
+    }
+
+    fn text(name: &str, text: &str) -> Self {
+        Self::new(name, "text", CalValue::Text(text.to_string()))
+    }
+
+    // A date or date-time, with the zone it is in
+    fn time(name: &str, value: DatePerhapsTime) -> Self {
+        let (kind, params) = match &value {
+            DatePerhapsTime::Date(_) => ("date", vec![]),
+            DatePerhapsTime::DateTime(CalendarDateTime::WithTimezone { tzid, .. }) => {
+                ("date-time", vec![("tzid".to_string(), tzid.clone())])
+            }
+            DatePerhapsTime::DateTime(_) => ("date-time", vec![]),
+        };
+        CalProperty { params, ..Self::new(name, kind, CalValue::Time(value)) }
+    }
+}
+
+impl CalComponent {
+    // The component as ics, for the ones icalendar doesn't have
+    fn to_ics(&self) -> IcsComponent {
+        let properties = self.properties.iter().map(|p| {
+            let mut params: Vec<ical::parser::Parameter<'static>> = p
+                .params
+                .iter()
+                .map(|(k, v)| ical::parser::Parameter { key: k.to_uppercase().into(), val: Some(v.clone().into()) })
+                .collect();
+            if p.kind == "date" {
+                params.push(ical::parser::Parameter { key: "VALUE".to_string().into(), val: Some("DATE".to_string().into()) });
+            }
+            let values: Vec<String> = p.values.iter().map(CalValue::to_ics).collect();
+            ical::parser::Property { name: p.name.to_uppercase().into(), val: values.join(",").into(), params }
+        });
+        IcsComponent {
+            name: self.name.to_uppercase().into(),
+            properties: properties.collect(),
+            components: self.components.iter().map(CalComponent::to_ics).collect(),
+        }
+    }
+
+    /// `[name, [properties], [components]]`, with properties as `[name, {parameters}, type, values...]`
+    fn to_jcal(&self) -> serde_json::Value {
+        let properties: Vec<serde_json::Value> = self
+            .properties
+            .iter()
+            .map(|p| {
+                let params: serde_json::Map<String, serde_json::Value> =
+                    p.params.iter().map(|(k, v)| (k.clone(), v.clone().into())).collect();
+                let mut property = vec![p.name.clone().into(), params.into(), p.kind.into()];
+                property.extend(p.values.iter().map(CalValue::to_jcal));
+                serde_json::Value::Array(property)
+            })
+            .collect();
+        let components: Vec<serde_json::Value> = self.components.iter().map(CalComponent::to_jcal).collect();
+        serde_json::json!([self.name, properties, components])
+    }
+
+    fn to_xcal(&self, depth: usize) -> String {
+        let indent = "  ".repeat(depth);
+        let mut res = format!("{indent}<{}>\n{indent}  <properties>\n", self.name);
+        for p in &self.properties {
+            let params: String = p
+                .params
+                .iter()
+                .map(|(k, v)| format!("<{k}><text>{}</text></{k}>", xml_escape(v)))
+                .collect();
+            let params = if params.is_empty() { params } else { format!("<parameters>{}</parameters>", params) };
+            let values: String = p.values.iter().map(|v| v.to_xcal(p.kind)).collect();
+            res += &format!("{indent}    <{name}>{params}{values}</{name}>\n", name = p.name);
+        }
+        res += &format!("{indent}  </properties>\n");
+        if !self.components.is_empty() {
+            res += &format!("{indent}  <components>\n");
+            for c in &self.components {
+                res += &c.to_xcal(depth + 2);
+            }
+            res += &format!("{indent}  </components>\n");
+        }
+        res + &format!("{indent}</{}>\n", self.name)
+    }
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Writes the same calendar as `to_ical` in jCal (RFC 7265).
+pub fn to_jcal(records: Vec<ExactRecord>, deterministic_tsmp: Option<chrono::DateTime<Utc>>, times: IcsTimes) -> Result<(String, Vec<Diagnostic>)> {
+    let (calendar, diagnostics) = to_cal_tree(records, deterministic_tsmp, times);
+    Ok((serde_json::to_string_pretty(&calendar.to_jcal())?, diagnostics))
+}
+
+/// Writes the same calendar as `to_ical` in xCal (RFC 6321).
+pub fn to_xcal(records: Vec<ExactRecord>, deterministic_tsmp: Option<chrono::DateTime<Utc>>, times: IcsTimes) -> Result<(String, Vec<Diagnostic>)> {
+    let (calendar, diagnostics) = to_cal_tree(records, deterministic_tsmp, times);
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<icalendar xmlns=\"urn:ietf:params:xml:ns:icalendar-2.0\">\n{}</icalendar>\n",
+        calendar.to_xcal(1)
+    );
+    Ok((xml, diagnostics))
+}
+
+/// Version of the JSON output, raised whenever a field changes or goes away.
+pub const JSON_SCHEMA_VERSION: u32 = 1;
+
+/// The JSON output: `{"version": 1, "records": [...]}`
+#[derive(Serialize)]
+struct JsonOutput {
+    version: u32,
+    records: Vec<JsonRecord>,
+}
+
+/// A record, told apart by its `type` field.
+/// Recurring events are written once per occurrence, all with the same source.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum JsonRecord {
+    Event {
+        name: String,
+        /// RFC 3339 time with the offset of the event's zone, or `YYYY-MM-DD` for all-day events
+        start: String,
+        /// Same form as `start`; the last day itself for all-day events
+        end: String,
+        all_day: bool,
+        /// IANA name of the zone, when one is set
+        timezone: Option<String>,
+        description: Option<String>,
+        properties: Vec<JsonProperty>,
+        source: Option<JsonSource>,
+    },
+    Todo {
+        name: String,
+        /// `YYYY-MM-DD`
+        due: Option<String>,
+        /// `needs-action`, `completed`, `in-process` or `cancelled`
+        status: &'static str,
+        source: Option<JsonSource>,
+    },
+    Note {
+        text: String,
+        source: Option<JsonSource>,
+    },
+}
+
+#[derive(Serialize)]
+struct JsonProperty {
+    name: String,
+    value: String,
+}
+
+/// Where a record starts in the blok file, both 1-based.
+#[derive(Serialize)]
+struct JsonSource {
+    line: usize,
+    column: usize,
+}
+
+impl From<Span> for JsonSource {
+    fn from(span: Span) -> Self {
+        JsonSource { line: span.line, column: span.col }
+    }
+}
+
+impl ExactDateTime {
+    // RFC 3339 with the offset the time has in its own zone
+    fn to_rfc3339(&self) -> Result<String> {
+        let t = self.to_chrono()?;
+        Ok(match self.tz {
+            TimeZoneChoice::Local => t.with_timezone(&Local).to_rfc3339(),
+            TimeZoneChoice::Utc => t.to_rfc3339_opts(cr::SecondsFormat::Secs, true),
+            TimeZoneChoice::Offset(offset) => t.with_timezone(&offset).to_rfc3339(),
+            TimeZoneChoice::Named(tz) => t.with_timezone(&tz).to_rfc3339(),
+        })
+    }
+}
+
+/// Start and end in ISO 8601: RFC 3339 times, or the first and last day of all-day events.
+fn iso_range(range: &ExactRange) -> Result<(String, String)> {
+    Ok(match range {
+        ExactRange::TimeRange(range) => (range.start.to_rfc3339()?, range.end.to_rfc3339()?),
+        ExactRange::AllDay(range) => (range.start.to_string(), range.end.to_string()),
+    })
+}
+
+fn event_description(event: &ExactEvent) -> Option<String> {
+    event
+        .notes
+        .as_ref()
+        .map(|notes| notes.description.trim_end_matches('\n').to_string())
+        .filter(|description| !description.is_empty())
+}
+
+fn todo_status(status: ical::TodoStatus) -> &'static str {
+    match status {
+        ical::TodoStatus::NeedsAction => "needs-action",
+        ical::TodoStatus::Completed => "completed",
+        ical::TodoStatus::InProcess => "in-process",
+        ical::TodoStatus::Cancelled => "cancelled",
+    }
+}
+
+fn json_event(event: &ExactEvent, range: &ExactRange) -> Result<JsonRecord> {
+    let (start, end) = iso_range(range)?;
+    let timezone = match range {
+        ExactRange::TimeRange(range) => match range.start.tz {
+            TimeZoneChoice::Named(tz) => Some(tz.name().to_string()),
+            _ => None,
+        },
+        ExactRange::AllDay(_) => None,
+    };
+    let description = event_description(event);
+    let properties = event
+        .notes
+        .iter()
+        .flat_map(|notes| &notes.properties)
+        .map(|p| JsonProperty { name: p.name.clone(), value: p.data.clone() })
+        .collect();
+    Ok(JsonRecord::Event {
+        name: event.name.clone(),
+        start,
+        end,
+        all_day: matches!(range, ExactRange::AllDay(_)),
+        timezone,
+        description,
+        properties,
+        source: event.span.map(JsonSource::from),
+    })
+}
+
+pub fn to_json(records: Vec<ExactRecord>) -> Result<(String, Vec<Diagnostic>)> {
+    let mut diagnostics = vec![];
+    let mut res = vec![];
+    for record in records.iter() {
+        match record {
+            ExactRecord::Event(event) => {
+                for range in event.occurrences() {
+                    match json_event(event, &range) {
+                        Ok(record) => res.push(record),
+                        Err(e) => diagnostics.push(Diagnostic::from_error("convert-event", &event.wrap_err(e))),
+                    }
+                }
+            }
+            ExactRecord::Todo(todo) => res.push(JsonRecord::Todo {
+                name: todo.name.clone(),
+                due: todo.due.map(|due| due.to_string()),
+                status: todo_status(todo.status),
+                source: todo.span.map(JsonSource::from),
+            }),
+            ExactRecord::Note(text, span) => res.push(JsonRecord::Note {
+                text: text.clone(),
+                source: span.map(JsonSource::from),
+            }),
+        }
+    }
+    let output = JsonOutput { version: JSON_SCHEMA_VERSION, records: res };
+    Ok((serde_json::to_string_pretty(&output)?, diagnostics))
+}
+
+/// Columns every CSV row can have. Properties get a column each after these, eg. `@location`.
+pub const CSV_COLUMNS: &[&str] = &["type", "name", "start", "end", "all_day", "description", "status", "due", "line"];
+
+/// Which columns the CSV output has, and what separates them.
+#[derive(Debug, Clone)]
+pub struct CsvOptions {
+    /// Names of the columns in order, or every column when None
+    pub columns: Option<Vec<String>>,
+    pub delimiter: u8,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions { columns: None, delimiter: b',' }
+    }
+}
+
+// The cells of a row by column name
+type CsvRow = BTreeMap<String, String>;
+
+fn csv_row(kind: &str, span: Option<Span>, cells: &[(&str, String)]) -> CsvRow {
+    let mut row: CsvRow = cells.iter().map(|(column, cell)| (column.to_string(), cell.clone())).collect();
+    row.insert("type".to_string(), kind.to_string());
+    if let Some(span) = span {
+        row.insert("line".to_string(), span.line.to_string());
+    }
+    row
+}
+
+/// Writes events, one row per occurrence, todos and notes as CSV.
+pub fn to_csv(records: Vec<ExactRecord>, options: &CsvOptions) -> Result<(String, Vec<Diagnostic>)> {
+    let mut diagnostics = vec![];
+    let mut rows = vec![];
+    let mut properties: Vec<String> = vec![];
+    for record in records.iter() {
+        match record {
+            ExactRecord::Event(event) => {
+                let props = event.notes.iter().flat_map(|notes| &notes.properties);
+                for prop in props.clone() {
+                    let column = format!("@{}", prop.name);
+                    if !properties.contains(&column) {
+                        properties.push(column);
+                    }
+                }
+                for range in event.occurrences() {
+                    let (start, end) = match iso_range(&range) {
+                        Ok(times) => times,
+                        Err(e) => {
+                            diagnostics.push(Diagnostic::from_error("convert-event", &event.wrap_err(e)));
+                            continue;
+                        }
+                    };
+                    let mut row = csv_row("event", event.span, &[
+                        ("name", event.name.clone()),
+                        ("start", start),
+                        ("end", end),
+                        ("all_day", matches!(range, ExactRange::AllDay(_)).to_string()),
+                        ("description", event_description(event).unwrap_or_default()),
+                    ]);
+                    for prop in props.clone() {
+                        row.insert(format!("@{}", prop.name), prop.data.clone());
+                    }
+                    rows.push(row);
+                }
+            }
+            ExactRecord::Todo(todo) => rows.push(csv_row("todo", todo.span, &[
+                ("name", todo.name.clone()),
+                ("status", todo_status(todo.status).to_string()),
+                ("due", todo.due.map(|due| due.to_string()).unwrap_or_default()),
+            ])),
+            ExactRecord::Note(text, span) => rows.push(csv_row("note", *span, &[("description", text.clone())])),
+        }
+    }
+
+    let columns: Vec<String> = match &options.columns {
+        Some(columns) => {
+            for column in columns {
+                if !CSV_COLUMNS.contains(&column.as_str()) && !properties.contains(column) {
+                    return Err(anyhow!(
+                        "Unknown CSV column: {}, expected one of {} or a property such as @location",
+                        column,
+                        CSV_COLUMNS.join(", ")
+                    ));
+                }
+            }
+            columns.clone()
+        }
+        None => CSV_COLUMNS.iter().map(|c| c.to_string()).chain(properties).collect(),
+    };
+    let mut wtr = csv::WriterBuilder::new().delimiter(options.delimiter).from_writer(vec![]);
+    wtr.write_record(&columns)?;
+    for row in rows {
+        wtr.write_record(columns.iter().map(|c| row.get(c).map(String::as_str).unwrap_or("")))?;
+    }
+    Ok((String::from_utf8(wtr.into_inner()?)?, diagnostics))
+}
+
+/// How an agenda is laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AgendaStyle {
+    Text,
+    Markdown,
+}
+
+// An entry under a day of the agenda
+struct AgendaItem {
+    // All-day events come first, then timed events by their start, then todos
+    order: (u8, (u32, u32, u32)),
+    when: String,
+    text: String,
+    notes: Vec<String>,
+}
+
+/// A length of time in the form durations are written in, eg. `1h30m` or `2d`.
+fn format_duration(length: chrono::Duration) -> String {
+    let minutes = length.num_minutes();
+    let parts = [(minutes / (24 * 60), "d"), (minutes / 60 % 24, "h"), (minutes % 60, "m")];
+    let res: String = parts
+        .iter()
+        .filter(|(n, _)| *n != 0)
+        .map(|(n, unit)| format!("{}{}", n, unit))
+        .collect();
+    if res.is_empty() {
+        "0m".to_string()
+    } else {
+        res
+    }
+}
+
+// The entries a single occurrence of an event makes, by day
+fn agenda_items(event: &ExactEvent, range: &ExactRange, notes: &[String]) -> Result<Vec<(ExactDate, AgendaItem)>> {
+    let mut res = vec![];
+    match range {
+        ExactRange::TimeRange(tr) => {
+            let length = tr.end.to_chrono()? - tr.start.to_chrono()?;
+            let hm = |t: &ExactTime| format!("{:02}:{:02}", t.hour, t.minute);
+            res.push((tr.start.date, AgendaItem {
+                order: (1, (tr.start.time.hour, tr.start.time.minute, tr.start.time.second)),
+                when: format!("{}-{}", hm(&tr.start.time), hm(&tr.end.time)),
+                text: format!("{} ({})", event.name, format_duration(length)),
+                notes: notes.to_vec(),
+            }));
+        }
+        ExactRange::AllDay(r) => {
+            let mut date = r.start;
+            while date <= r.end {
+                res.push((date, AgendaItem {
+                    order: (0, (0, 0, 0)),
+                    when: "all day".to_string(),
+                    text: event.name.clone(),
+                    notes: notes.to_vec(),
+                }));
+                date = date.succ()?;
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// Lists records by day and in time order, with the length and notes of events.
+/// Notes and todos without a due date are listed after the days.
+pub fn to_agenda(records: Vec<ExactRecord>, style: AgendaStyle) -> Result<(String, Vec<Diagnostic>)> {
+    let mut diagnostics = vec![];
+    let mut days: BTreeMap<ExactDate, Vec<AgendaItem>> = BTreeMap::new();
+    let mut todos = vec![];
+    let mut notes = vec![];
+    for record in records.iter() {
+        match record {
+            ExactRecord::Event(event) => {
+                let description: Vec<String> = event_description(event)
+                    .map(|d| d.lines().map(str::to_string).collect())
+                    .unwrap_or_default();
+                for range in event.occurrences() {
+                    match agenda_items(event, &range, &description) {
+                        Ok(items) => {
+                            for (date, item) in items {
+                                days.entry(date).or_default().push(item);
+                            }
+                        }
+                        Err(e) => diagnostics.push(Diagnostic::from_error("convert-event", &event.wrap_err(e))),
+                    }
+                }
+            }
+            ExactRecord::Todo(todo) => {
+                let done = matches!(todo.status, ical::TodoStatus::Completed | ical::TodoStatus::Cancelled);
+                let item = AgendaItem {
+                    order: (2, (0, 0, 0)),
+                    when: if done { "[x]" } else { "[ ]" }.to_string(),
+                    text: todo.name.clone(),
+                    notes: vec![],
+                };
+                match todo.due {
+                    Some(due) => days.entry(due).or_default().push(item),
+                    None => todos.push(item),
+                }
+            }
+            ExactRecord::Note(text, _) => notes.push(text.clone()),
+        }
+    }
+
+    let item = |item: &AgendaItem| -> String {
+        let mut res = match style {
+            AgendaStyle::Text => format!("  {:<11}  {}\n", item.when, item.text),
+            AgendaStyle::Markdown if item.order.0 == 2 => format!("- {} {}\n", item.when, item.text),
+            AgendaStyle::Markdown => format!("- **{}** {}\n", item.when, item.text),
+        };
+        for note in &item.notes {
+            match style {
+                AgendaStyle::Text => res += &format!("{:15}{}\n", "", note),
+                AgendaStyle::Markdown => res += &format!("  {}\n", note),
+            }
         }
+        res
+    };
+    let heading = |title: &str| match style {
+        AgendaStyle::Text => format!("{}\n", title),
+        AgendaStyle::Markdown => format!("## {}\n\n", title),
+    };
+    let mut sections = vec![];
+    for (date, mut items) in days {
+        items.sort_by_key(|item| item.order);
+        let title = date.to_chrono()?.format("%a %Y-%m-%d").to_string();
+        sections.push(heading(&title) + &items.iter().map(item).collect::<String>());
     }
-    Ok(String::from_utf8(wtr.into_inner()?)?)
+    if !todos.is_empty() {
+        sections.push(heading("Todos") + &todos.iter().map(item).collect::<String>());
+    }
+    if !notes.is_empty() {
+        let lines: String = notes
+            .iter()
+            .map(|note| match style {
+                AgendaStyle::Text => format!("  {}\n", note),
+                AgendaStyle::Markdown if note.starts_with("- ") => format!("{}\n", note),
+                AgendaStyle::Markdown => format!("- {}\n", note),
+            })
+            .collect();
+        sections.push(heading("Notes") + &lines);
+    }
+    Ok((sections.join("\n"), diagnostics))
+}
+
+// An active Org timestamp, eg. `<2023-03-10 Fri 09:00>`
+fn org_timestamp(date: ExactDate, time: Option<ExactTime>) -> Result<String> {
+    let date = date.to_chrono()?.format("%Y-%m-%d %a");
+    Ok(match time {
+        Some(t) => format!("<{} {:02}:{:02}>", date, t.hour, t.minute),
+        None => format!("<{}>", date),
+    })
+}
+
+/// The range as an Org timestamp, eg. `<2023-03-10 Fri 09:00-10:30>` or `<2023-07-01 Sat>--<2023-07-14 Fri>`.
+/// Org has no time zones, so times are on the clock of the start's zone.
+fn org_range(range: &ExactRange) -> Result<String> {
+    Ok(match range {
+        ExactRange::TimeRange(tr) => {
+            let end = ExactDateTime::from_chrono_in(tr.end.to_chrono()?, tr.start.tz);
+            if end.date == tr.start.date {
+                let start = org_timestamp(tr.start.date, Some(tr.start.time))?;
+                format!("{}-{:02}:{:02}>", start.trim_end_matches('>'), end.time.hour, end.time.minute)
+            } else {
+                format!("{}--{}", org_timestamp(tr.start.date, Some(tr.start.time))?, org_timestamp(end.date, Some(end.time))?)
+            }
+        }
+        ExactRange::AllDay(r) if r.start == r.end => org_timestamp(r.start, None)?,
+        ExactRange::AllDay(r) => format!("{}--{}", org_timestamp(r.start, None)?, org_timestamp(r.end, None)?),
+    })
+}
+
+// Body text, indented where a line would otherwise start a heading
+fn org_body(text: &str) -> String {
+    text.lines()
+        .map(|line| if line.starts_with('*') { format!(" {}\n", line) } else { format!("{}\n", line) })
+        .collect()
+}
+
+/// Writes events and todos as Org headings, with loose notes at the top of the file.
+/// A recurring event is one heading with a timestamp for each date it happens on.
+pub fn to_org(records: Vec<ExactRecord>) -> Result<(String, Vec<Diagnostic>)> {
+    let mut diagnostics = vec![];
+    let mut preamble = String::new();
+    let mut headings = String::new();
+    for record in records.iter() {
+        match record {
+            ExactRecord::Event(event) => {
+                headings += &format!("* {}\n", event.name);
+                let properties: Vec<_> = event.notes.iter().flat_map(|notes| &notes.properties).collect();
+                // The drawer has to come right after the heading to be read as one
+                if !properties.is_empty() {
+                    headings += ":PROPERTIES:\n";
+                    for prop in properties {
+                        headings += &format!(":{}: {}\n", prop.name, prop.data);
+                    }
+                    headings += ":END:\n";
+                }
+                for range in event.occurrences() {
+                    match org_range(&range) {
+                        Ok(timestamp) => headings += &format!("{}\n", timestamp),
+                        Err(e) => diagnostics.push(Diagnostic::from_error("convert-event", &event.wrap_err(e))),
+                    }
+                }
+                if let Some(description) = event_description(event) {
+                    headings += &org_body(&description);
+                }
+            }
+            ExactRecord::Todo(todo) => {
+                let keyword = match todo.status {
+                    ical::TodoStatus::Completed | ical::TodoStatus::Cancelled => "DONE",
+                    _ => "TODO",
+                };
+                headings += &format!("* {} {}\n", keyword, todo.name);
+                if let Some(due) = todo.due {
+                    match org_timestamp(due, None) {
+                        Ok(timestamp) => headings += &format!("DEADLINE: {}\n", timestamp),
+                        Err(e) => diagnostics.push(Diagnostic::from_error("convert-todo", &todo.wrap_err(e))),
+                    }
+                }
+            }
+            ExactRecord::Note(text, _) => preamble += &org_body(text),
+        }
+    }
+    Ok((preamble + &headings, diagnostics))
 }
 
 // test
@@ -174,6 +1321,13 @@ mod tests {
     
     use anyhow::Result;
 
+    // Records of a blok source, resolved at the start of 2023
+    fn resolve_source(source: &str) -> Result<Vec<ExactRecord>> {
+        let (records, _) = crate::tb_to_records(source)?;
+        let (resolved, _) = crate::records_to_resolved(records, ExactDateTime::from_ymd_hms(2023, 1, 1, 0, 0, 0))?;
+        Ok(resolved)
+    }
+
     #[test]
     fn test_timestamp() -> Result<()>{
         let timestamp = 1680533997811;
@@ -186,4 +1340,270 @@ mod tests {
             Err(anyhow::anyhow!("failed to parse timestamp"))
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_all_day_roundtrip() -> Result<()>{
+        use crate::importer::ics_to_records;
+        use crate::ir::ExactDateRange;
+        use std::str::FromStr;
+
+        let range = ExactDateRange {
+            start: ExactDate::from_ymd(2023, 7, 1),
+            end: ExactDate::from_ymd(2023, 7, 14),
+        };
+        let event = ExactEvent {
+            range: ExactRange::AllDay(range),
+            name: "Vacation".to_string(),
+            notes: None,
+            span: None,
+            recurrence: None,
+        };
+        let (ics, diagnostics) = to_ical(vec![ExactRecord::Event(event)], None, IcsTimes::default());
+        assert!(diagnostics.is_empty());
+        assert!(ics.contains("DTEND;VALUE=DATE:20230715"));
+        let cal = ical::Calendar::from_str(&ics).map_err(|e| anyhow!(e))?;
+        match &ics_to_records(&cal)[0] {
+            ExactRecord::Event(ExactEvent { range: ExactRange::AllDay(r), .. }) => assert_eq!(*r, range),
+            r => panic!("expected all-day event, got {:?}", r),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_times() -> Result<()>{
+        let source = "/tz America/New_York\n2023-3-\n{fri and not --17}\n17:00 Weekly review\n";
+        let convert = |times| -> Result<String> {
+            let (ics, diagnostics) = to_ical(resolve_source(source)?, None, times);
+            assert!(diagnostics.is_empty());
+            Ok(ics)
+        };
+
+        let zoned = convert(IcsTimes::Zoned)?;
+        assert!(zoned.contains("DTSTART;TZID=America/New_York:20230303T170000"));
+        assert!(zoned.contains("EXDATE;TZID=America/New_York:20230317T170000"));
+        assert!(zoned.contains("TZID:America/New_York\r\nBEGIN:STANDARD"));
+        assert!(zoned.contains("BEGIN:DAYLIGHT\r\nDTSTART:20230312T020000\r\nTZOFFSETFROM:-0500\r\nTZOFFSETTO:-0400"));
+        assert!(zoned.find("BEGIN:VTIMEZONE") < zoned.find("BEGIN:VEVENT"));
+
+        let floating = convert(IcsTimes::Floating)?;
+        assert!(floating.contains("DTSTART:20230303T170000\r\n"));
+        assert!(floating.contains("UNTIL=20230331T170000\r\n"));
+        assert!(!floating.contains("VTIMEZONE"));
+
+        // The series is written date by date, as its UTC time changes with the clocks
+        let utc = convert(IcsTimes::Utc)?;
+        assert!(utc.contains("DTSTART:20230303T220000Z"));
+        assert!(utc.contains("DTSTART:20230310T220000Z"));
+        assert!(utc.contains("DTSTART:20230324T210000Z"));
+        assert!(!utc.contains("RRULE") && !utc.contains("20230317"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_vtimezone() -> Result<()>{
+        let mut out = String::new();
+        write_component(&vtimezone(Tz::America__New_York, 2000, 2010).to_ics(), &mut out)?;
+        // The rules changed in 2007, and the ones from then on keep going
+        assert!(out.contains("DTSTART:20000402T020000\r\nTZOFFSETFROM:-0500\r\nTZOFFSETTO:-0400\r\nTZNAME:EDT\r\nRRULE:FREQ=YEARLY;BYMONTH=4;BYDAY=1SU;UNTIL=20060402T070000Z\r\n"));
+        assert!(out.contains("DTSTART:20071104T020000\r\nTZOFFSETFROM:-0400\r\nTZOFFSETTO:-0500\r\nTZNAME:EST\r\nRRULE:FREQ=YEARLY;BYMONTH=11;BYDAY=1SU\r\n"));
+        assert_eq!(out.matches("BEGIN:DAYLIGHT").count(), 2);
+
+        // A series that ends centuries later doesn't list every year
+        let mut out = String::new();
+        write_component(&vtimezone(Tz::America__New_York, 2023, 9999).to_ics(), &mut out)?;
+        assert_eq!(out.matches("BEGIN:").count(), 4);
+        assert!(!out.contains("UNTIL"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_csv() -> Result<()>{
+        let source = "a note\n/tz +02:00\n2023-3-10\n9am Standup\n- daily\n@room \"A, 1\"\n2023-7-1~2023-7-2 Trip\n/t pack\n";
+        let (csv, diagnostics) = to_csv(resolve_source(source)?, &CsvOptions::default())?;
+        assert!(diagnostics.is_empty());
+        assert_eq!(
+            csv.lines().collect::<Vec<_>>(),
+            vec![
+                "type,name,start,end,all_day,description,status,due,line,@room",
+                "note,,,,,a note,,,1,",
+                "event,Standup,2023-03-10T09:00:00+02:00,2023-03-10T09:30:00+02:00,false,- daily,,,4,\"A, 1\"",
+                "event,Trip,2023-07-01,2023-07-02,true,,,,7,",
+                "todo,pack,,,,,needs-action,,8,",
+            ]
+        );
+
+        let options = CsvOptions {
+            columns: Some(vec!["name".to_string(), "@room".to_string()]),
+            delimiter: b';',
+        };
+        let (csv, _) = to_csv(resolve_source(source)?, &options)?;
+        assert_eq!(csv.lines().nth(2), Some("Standup;A, 1"));
+
+        // An event that can't be written is reported, and the rest are still written
+        let mut records = resolve_source(source)?;
+        records.push(ExactRecord::Event(ExactEvent {
+            range: ExactRange::TimeRange(crate::ir::ExactTimeRange {
+                start: ExactDateTime::from_ymd_hms(2023, 2, 30, 9, 0, 0),
+                end: ExactDateTime::from_ymd_hms(2023, 2, 30, 10, 0, 0),
+            }),
+            name: "Nowhere".to_string(),
+            notes: None,
+            span: None,
+            recurrence: None,
+        }));
+        let (csv, diagnostics) = to_csv(records, &CsvOptions::default())?;
+        assert_eq!(csv.lines().count(), 5);
+        assert_eq!(diagnostics.len(), 1);
+
+        let options = CsvOptions {
+            columns: Some(vec!["@nosuch".to_string()]),
+            ..Default::default()
+        };
+        assert!(to_csv(resolve_source(source)?, &options).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_agenda() -> Result<()>{
+        let source = "a note\n2023-1-10\n9am~10:30am Meeting\n- agenda\n2023-1-9~2023-1-10 Trip\n10pm~1am Night shift\n/t pack\n";
+        let agenda = |style| -> Result<String> {
+            let (agenda, diagnostics) = to_agenda(resolve_source(source)?, style)?;
+            assert!(diagnostics.is_empty());
+            Ok(agenda)
+        };
+        assert_eq!(
+            agenda(AgendaStyle::Text)?,
+            "Mon 2023-01-09\n  all day      Trip\n\n\
+             Tue 2023-01-10\n  all day      Trip\n  09:00-10:30  Meeting (1h30m)\n               - agenda\n  22:00-01:00  Night shift (3h)\n\n\
+             Todos\n  [ ]          pack\n\n\
+             Notes\n  a note\n"
+        );
+        assert_eq!(
+            agenda(AgendaStyle::Markdown)?,
+            "## Mon 2023-01-09\n\n- **all day** Trip\n\n\
+             ## Tue 2023-01-10\n\n- **all day** Trip\n- **09:00-10:30** Meeting (1h30m)\n  - agenda\n- **22:00-01:00** Night shift (3h)\n\n\
+             ## Todos\n\n- [ ] pack\n\n\
+             ## Notes\n\n- a note\n"
+        );
+        assert_eq!(format_duration(chrono::Duration::minutes(2 * 24 * 60 + 5)), "2d5m");
+        Ok(())
+    }
+
+    #[test]
+    fn test_org() -> Result<()>{
+        let source = "a note\n2023-1-10\n9am~10:30am Meeting\n- agenda\n* not a heading\n@room \"A 1\"\n10pm~1am Night shift\n2023-1-9~2023-1-10 Trip\n/t pack\n";
+        let mut resolved = resolve_source(source)?;
+        if let Some(ExactRecord::Todo(todo)) = resolved.last_mut() {
+            todo.status = ical::TodoStatus::Completed;
+            todo.due = Some(ExactDate::from_ymd(2023, 1, 11));
+        }
+        let (org, diagnostics) = to_org(resolved)?;
+        assert!(diagnostics.is_empty());
+        assert_eq!(
+            org,
+            "a note\n\
+             * Meeting\n:PROPERTIES:\n:room: A 1\n:END:\n<2023-01-10 Tue 09:00-10:30>\n- agenda\n * not a heading\n\
+             * Night shift\n<2023-01-10 Tue 22:00>--<2023-01-11 Wed 01:00>\n\
+             * Trip\n<2023-01-09 Mon>--<2023-01-10 Tue>\n\
+             * DONE pack\nDEADLINE: <2023-01-11 Wed>\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_json() -> Result<()>{
+        let source = "a loose note\n2023-3-10\n9am America/Los_Angeles~6pm America/New_York Flight\n- Window seat\n@gate B12\n2023-7-1~2023-7-2 Trip\n/t pack\n";
+        let (json, diagnostics) = to_json(resolve_source(source)?)?;
+        assert!(diagnostics.is_empty());
+        let json: serde_json::Value = serde_json::from_str(&json)?;
+        assert_eq!(json["version"], JSON_SCHEMA_VERSION);
+        let records = json["records"].as_array().unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[0], serde_json::json!({
+            "type": "note",
+            "text": "a loose note",
+            "source": {"line": 1, "column": 1},
+        }));
+        assert_eq!(records[1], serde_json::json!({
+            "type": "event",
+            "name": "Flight",
+            "start": "2023-03-10T09:00:00-08:00",
+            "end": "2023-03-10T18:00:00-05:00",
+            "all_day": false,
+            "timezone": "America/Los_Angeles",
+            "description": "- Window seat",
+            "properties": [{"name": "gate", "value": "B12"}],
+            "source": {"line": 3, "column": 1},
+        }));
+        assert_eq!(records[2]["start"], "2023-07-01");
+        assert_eq!(records[2]["end"], "2023-07-02");
+        assert_eq!(records[2]["all_day"], true);
+        assert_eq!(records[3]["type"], "todo");
+        assert_eq!(records[3]["status"], "needs-action");
+
+        // An event with only a start time ends in the zone it starts in
+        let (json, _) = to_json(resolve_source("/tz America/New_York\n2023-3-10\n9am Standup\n")?)?;
+        let json: serde_json::Value = serde_json::from_str(&json)?;
+        assert_eq!(json["records"][0]["start"], "2023-03-10T09:00:00-05:00");
+        assert_eq!(json["records"][0]["end"], "2023-03-10T09:30:00-05:00");
+        Ok(())
+    }
+
+    const CAL_SOURCE: &str = "/tz America/New_York\n2023-3-\n{fri and not --17}\n17:00 Review & plan\n- one, two\n- three\n/t pack\n";
+
+    #[test]
+    fn test_jcal() -> Result<()>{
+        let tsmp = Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap();
+        let (jcal, diagnostics) = to_jcal(resolve_source(CAL_SOURCE)?, Some(tsmp), IcsTimes::Zoned)?;
+        assert!(diagnostics.is_empty());
+        let jcal: serde_json::Value = serde_json::from_str(&jcal)?;
+        assert_eq!(jcal[0], "vcalendar");
+        assert!(jcal[1].as_array().unwrap().contains(&serde_json::json!(["version", {}, "text", "2.0"])));
+        let components = jcal[2].as_array().unwrap();
+        assert_eq!(components.iter().map(|c| c[0].as_str().unwrap()).collect::<Vec<_>>(), ["vtimezone", "vevent", "vtodo"]);
+        assert!(components[0][2].as_array().unwrap().contains(&serde_json::json!(["daylight", [
+            ["dtstart", {}, "date-time", "2023-03-12T02:00:00"],
+            ["tzoffsetfrom", {}, "utc-offset", "-05:00"],
+            ["tzoffsetto", {}, "utc-offset", "-04:00"],
+            ["tzname", {}, "text", "EDT"],
+        ], []])));
+        let event = components[1][1].as_array().unwrap();
+        let uid = Uuid::new_v3(&Uuid::NAMESPACE_URL, b"0").to_string();
+        for property in [
+            serde_json::json!(["dtstart", {"tzid": "America/New_York"}, "date-time", "2023-03-03T17:00:00"]),
+            serde_json::json!(["dtstamp", {}, "date-time", "2023-01-01T00:00:00Z"]),
+            serde_json::json!(["rrule", {}, "recur", {"freq": "WEEKLY", "byday": "FR", "until": "2023-03-31T21:00:00Z"}]),
+            serde_json::json!(["exdate", {"tzid": "America/New_York"}, "date-time", "2023-03-17T17:00:00"]),
+            serde_json::json!(["summary", {}, "text", "Review & plan"]),
+            serde_json::json!(["description", {}, "text", "- one, two\n- three\n"]),
+            serde_json::json!(["uid", {}, "text", uid]),
+        ] {
+            assert!(event.contains(&property), "missing {}", property);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_xcal() -> Result<()>{
+        let (xcal, diagnostics) = to_xcal(resolve_source(CAL_SOURCE)?, None, IcsTimes::Utc)?;
+        assert!(diagnostics.is_empty());
+        assert!(xcal.starts_with("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<icalendar xmlns=\"urn:ietf:params:xml:ns:icalendar-2.0\">\n  <vcalendar>\n"));
+        assert!(xcal.contains("<dtstart><date-time>2023-03-03T22:00:00Z</date-time></dtstart>"));
+        assert!(xcal.contains("<dtstart><date-time>2023-03-24T21:00:00Z</date-time></dtstart>"));
+        assert!(xcal.contains("<summary><text>Review &amp; plan</text></summary>"));
+        assert!(xcal.contains("<vtodo>"));
+        assert!(!xcal.contains("vtimezone"));
+        let rule = RecurrenceRule {
+            freq: Frequency::Weekly,
+            interval: 1,
+            by_day: vec![(0, chrono::Weekday::Mon), (0, chrono::Weekday::Wed)],
+            by_month_day: vec![],
+            by_month: vec![],
+        };
+        let until = NaiveDate::from_ymd_opt(2023, 3, 31).unwrap();
+        assert_eq!(rrule(&rule, until.into()).to_ics(), "FREQ=WEEKLY;BYDAY=MO,WE;UNTIL=20230331");
+        assert_eq!(rrule(&rule, until.into()).to_xcal("recur"),
+            "<recur><freq>WEEKLY</freq><until>2023-03-31</until><byday>MO</byday><byday>WE</byday></recur>");
+        Ok(())
+    }
+}
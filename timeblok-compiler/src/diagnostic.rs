@@ -0,0 +1,133 @@
+use std::fmt::Display;
+
+use crate::ir::{Span, SpannedError};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+/// A problem found while compiling a blok file.
+/// `code` is a short, stable identifier of the kind of problem, eg. `resolve-event`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Option<Span>,
+    pub code: &'static str,
+}
+
+impl Diagnostic {
+    pub fn error<T: Display>(code: &'static str, message: T) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            message: message.to_string(),
+            span: None,
+            code,
+        }
+    }
+
+    pub fn warning<T: Display>(code: &'static str, message: T) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..Self::error(code, message)
+        }
+    }
+
+    /// Output that is not a problem, eg. the value printed by `/print`.
+    pub fn info<T: Display>(code: &'static str, message: T) -> Self {
+        Diagnostic {
+            severity: Severity::Info,
+            ..Self::error(code, message)
+        }
+    }
+
+    /// Builds an error diagnostic, taking the span from the error if it carries one.
+    pub fn from_error(code: &'static str, error: &anyhow::Error) -> Self {
+        match error.downcast_ref::<SpannedError>() {
+            Some(spanned) => Self::error(code, &spanned.error).with_span(spanned.span),
+            None => Self::error(code, error),
+        }
+    }
+
+    pub fn with_span(self, span: Span) -> Self {
+        Diagnostic {
+            span: Some(span),
+            ..self
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    /// Renders the diagnostic along with the offending line of `source`.
+    pub fn render(&self, source: &str) -> String {
+        let mut res = format!("{}[{}]: {}", self.severity, self.code, self.message);
+        if let Some(span) = self.span {
+            let line = source.lines().nth(span.line.saturating_sub(1)).unwrap_or("");
+            let gutter = " ".repeat(span.line.to_string().len());
+            // Only underline up to the end of the first line of the span
+            let width = (span.end - span.start)
+                .min(line.chars().count().saturating_sub(span.col.saturating_sub(1)))
+                .max(1);
+            res.push_str(&format!("\n{} --> {}", gutter, span));
+            res.push_str(&format!("\n{} |", gutter));
+            res.push_str(&format!("\n{} | {}", span.line, line));
+            res.push_str(&format!(
+                "\n{} | {}{}",
+                gutter,
+                " ".repeat(span.col.saturating_sub(1)),
+                "^".repeat(width)
+            ));
+        }
+        res
+    }
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Info => f.write_str("info"),
+            Severity::Warning => f.write_str("warning"),
+            Severity::Error => f.write_str("error"),
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.span {
+            Some(span) => write!(f, "{}[{}]: {}: {}", self.severity, self.code, span, self.message),
+            None => write!(f, "{}[{}]: {}", self.severity, self.code, self.message),
+        }
+    }
+}
+
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics.iter().any(Diagnostic::is_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let source = "2023-4-4\n25:00 too late\n";
+        let span = Span {
+            start: 9,
+            end: 23,
+            line: 2,
+            col: 1,
+        };
+        let diag = Diagnostic::from_error("resolve-event", &span.wrap(anyhow::anyhow!("bad hour")));
+        assert_eq!(diag.span, Some(span));
+        assert_eq!(
+            diag.render(source),
+            "error[resolve-event]: bad hour\n  --> line 2, column 1\n  |\n2 | 25:00 too late\n  | ^^^^^^^^^^^^^^"
+        );
+    }
+}
@@ -1,11 +1,13 @@
-use crate::ir::filter::{Filter, BDF};
+use crate::diagnostic::Diagnostic;
+use crate::ir::filter::BDF;
 use crate::ir::NumVal::Number;
+use crate::ir::ident::DynFilter;
 use crate::ir::{
-    ident::IdentData, Date, DateTime, ExactDate, ExactDateTime, FlexDate, FlexField, NumVal,
+    ident::IdentData, Date, DateTime, ExactDate, ExactDateTime, FlexDate, FlexField, NumVal, YearDate,
 };
 use crate::resolver::resolve_date;
 use anyhow::Result;
-use chrono::NaiveDate;
+use chrono::{Datelike, NaiveDate};
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::rc::Rc;
@@ -16,14 +18,14 @@ pub struct Environment {
     pub parent: Option<Rc<Environment>>,
     pub current: DateTime,
     pub namespace: RefCell<HashMap<String, IdentData>>,
+    // Only the root environment collects diagnostics, see `report`
+    pub diagnostics: RefCell<Vec<Diagnostic>>,
 }
 
 pub struct EnvIterator<'a> {
     env: &'a Environment,
-    cur: Date,
-    cur_date: NaiveDate,
+    cur_date: Option<NaiveDate>,
     filter: BDF<ExactDate>,
-    fit_date: Date,
 }
 
 impl Environment {
@@ -37,6 +39,7 @@ impl Environment {
             parent,
             current,
             namespace: RefCell::new(HashMap::new()),
+            diagnostics: RefCell::new(vec![]),
         }
     }
     pub fn from_exact(dt: ExactDateTime) -> Self {
@@ -63,8 +66,23 @@ impl Environment {
         self.namespace.borrow_mut().remove(name);
         Ok(())
     }
+
+    /// Records a diagnostic in the root environment.
+    /// Filters are checked once per date, so repeated diagnostics are only kept once.
+    pub fn report(&self, diagnostic: Diagnostic) {
+        match &self.parent {
+            Some(parent) => parent.report(diagnostic),
+            None => {
+                let mut diagnostics = self.diagnostics.borrow_mut();
+                if !diagnostics.contains(&diagnostic) {
+                    diagnostics.push(diagnostic);
+                }
+            }
+        }
+    }
 }
 
+#[allow(dead_code)]
 impl Environment {
     // might come in handy later
     fn get_loc(&self, i: usize) -> Option<NumVal> {
@@ -89,52 +107,85 @@ impl Environment {
     }
 }
 
+// The most specific date the environment is bound to: fields up to the last specified one are
+// fixed, inheriting unspecified ones from the resolved date, the rest are free.
+// eg. `-2-` under 2023-1-5 fits 2023-2-*
 fn max_fit_date(env: &Environment) -> Option<Date> {
-    let date = &env.current.date;
-    match date {
-        None => max_fit_date(env.parent.as_ref().unwrap()),
-        Some(date) => {
-            let mut ndate = Date::new();
-            if let Number(year) = date.year {
-                ndate.year = Number(year);
-            } else {
-                return Some(ndate);
-            }
-            if let Number(month) = date.month {
-                ndate.month = Number(month);
-            } else {
-                return Some(ndate);
-            }
-            if let Number(day) = date.day {
-                ndate.day = Number(day);
-            } else {
-                return Some(ndate);
+    let date = match &env.current.date {
+        Some(date) => date,
+        None => return max_fit_date(env.parent.as_ref()?),
+    };
+    let fields = [date.year, date.month, date.day];
+    let last = match fields.iter().rposition(|f| matches!(f, Number(_))) {
+        Some(last) => last,
+        None => {
+            return match &env.parent {
+                Some(parent) => max_fit_date(parent),
+                None => Some(Date::new()),
             }
-            Some(ndate)
         }
+    };
+    let base = &env.date_time.date;
+    let inherited = [base.year as i64, base.month as i64, base.day as i64];
+    let mut res = [NumVal::Unsure; 3];
+    for i in 0..=last {
+        res[i] = match fields[i] {
+            Number(n) => Number(n),
+            NumVal::Unsure => Number(inherited[i]),
+        };
     }
+    Some(Date {
+        year: res[0],
+        month: res[1],
+        day: res[2],
+        relative: None,
+        year_date: None,
+    })
 }
 
 impl Iterator for EnvIterator<'_> {
     type Item = Date;
     fn next(&mut self) -> Option<Self::Item> {
-        // This conveniently assumes dates are continuous, don't use for non-continuous filters
-        let cur_date = Date::from_naive(self.cur_date);
+        let naive = self.cur_date?;
+        let cur_date = Date::from_naive(naive);
         if !self
             .filter
             .check(&(resolve_date(&cur_date, self.env).ok())?, Some(self.env))
         {
             return None;
         }
-        let new_date = self.cur_date + chrono::Duration::days(1);
-        self.cur_date = new_date;
+        self.cur_date = naive.succ_opt();
         Some(cur_date)
     }
 }
 
+// The week the environment is bound to by a week occasion without a day, eg. `2023-W15`
+fn scope_week(env: &Environment) -> Option<NaiveDate> {
+    match &env.current.date {
+        Some(date @ Date { year_date: Some(YearDate::Week(_, None)), .. }) => {
+            resolve_date(date, env).ok()?.to_chrono().ok()
+        }
+        Some(_) => None,
+        None => scope_week(env.parent.as_ref()?),
+    }
+}
+
 impl Environment {
-    pub fn iter(&self) -> EnvIterator {
-        let fit_date = max_fit_date(self).unwrap();
+    /// The dates of the year, month, day or week the environment is scoped to, ending at the first date out of it.
+    pub fn iter(&self) -> EnvIterator<'_> {
+        if let Some(monday) = scope_week(self) {
+            let week = monday.iso_week();
+            let filter = DynFilter {
+                filter: Rc::new(move |date: &ExactDate, _| matches!(date.to_chrono(), Ok(d) if d.iso_week() == week)),
+                name: "week".to_string(),
+            };
+            return EnvIterator {
+                env: self,
+                cur_date: Some(monday),
+                filter: Box::new(filter),
+            };
+        }
+        let fit_date = max_fit_date(self).unwrap_or_default();
         let filter = Box::new(FlexDate {
             day: Box::new(FlexField::NumVal(fit_date.day)) as BDF<NumVal>,
             month: Box::new(FlexField::NumVal(fit_date.month)) as BDF<NumVal>,
@@ -144,18 +195,16 @@ impl Environment {
             Number(n) => n,
             _ => 1,
         };
+        // An invalid date, eg. 2023-2-30, has nothing to iterate over
         let cur_date = NaiveDate::from_ymd_opt(
             filldat(fit_date.year) as i32,
             filldat(fit_date.month) as u32,
             filldat(fit_date.day) as u32,
-        )
-        .unwrap();
+        );
         EnvIterator {
             env: self,
-            cur: fit_date,
             cur_date,
             filter: filter as BDF<ExactDate>,
-            fit_date,
         }
     }
 }
@@ -167,17 +216,17 @@ mod tests {
     fn test_env() {
         use super::*;
         let env = Environment::from_exact(ExactDateTime::from_ymd_hms(2023, 1, 1, 1, 1, 1));
-        let mut daynum = 1;
-        for date in env.iter() {
+        for (daynum, date) in (1..).zip(env.iter()) {
             assert_eq!(
                 date,
                 Date {
                     year: Number(2023),
                     month: Number(1),
                     day: Number(daynum),
+                    relative: None,
+                    year_date: None,
                 }
             );
-            daynum += 1;
         }
     }
 }
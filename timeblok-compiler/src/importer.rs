@@ -9,9 +9,10 @@ use crate::{
     ir::{filter::Filter, Date, ExactDate},
     resolver::resolve_date,
 };
-use chrono::NaiveDate;
-use icalendar::{Calendar, Component};
-use crate::ir::{ExactDateTime, ExactEvent, ExactRange, ExactRecord, ExactTimeRange, Todo, ExactNotes, ExactProperty};
+use chrono::{NaiveDate, NaiveDateTime, TimeZone, Weekday};
+use icalendar::{Calendar, Component, DatePerhapsTime};
+use crate::ir::{ExactDateRange, ExactDateTime, ExactEvent, ExactRange, ExactRecord, ExactTimeRange, Todo, ExactNotes, ExactProperty, TimeZoneChoice};
+use crate::ir::recurrence::{Frequency, Recurrence, RecurrenceRule};
 use anyhow::{Result, anyhow};
 use crate::utils::get_dir;
 #[cfg(not(target_family = "wasm"))]
@@ -24,8 +25,16 @@ pub struct SetFilter {
 
 impl Filter<Date> for SetFilter {
     fn check(&self, value: &Date, env: Option<&Environment>) -> bool {
-        self.dates
-            .contains(&resolve_date(value, env.unwrap()).unwrap())
+        match env.map(|env| resolve_date(value, env)) {
+            Some(Ok(date)) => self.dates.contains(&date),
+            _ => false,
+        }
+    }
+    fn window(&self, _env: Option<&Environment>) -> Option<ExactDateRange> {
+        Some(ExactDateRange {
+            start: *self.dates.iter().min()?,
+            end: *self.dates.iter().max()?,
+        })
     }
 }
 
@@ -40,25 +49,19 @@ impl SetFilter {
 
     pub fn from_ics(cal: &Calendar) -> Self {
         let mut dates = HashSet::new();
-        for c in cal.iter() {
-            if let Some(event) = c.as_event() {
-                match (event.get_start(), event.get_end()) {
-                    (Some(st), Some(nd)) => {
-                        let est = ExactDate::from_date_perhaps_time(st).to_naive();
-                        let end = ExactDate::from_date_perhaps_time(nd).to_naive();
-                        for i in 0..=(end - est).num_days() {
-                            dates.insert(ExactDate::from_naive(est + chrono::Duration::days(i)));
-                        }
-                    }
-                    (Some(st), None) => {
-                        let est = ExactDate::from_date_perhaps_time(st).to_naive();
-                        dates.insert(ExactDate::from_naive(est));
-                    }
-                    (None, Some(nd)) => {
-                        let end = ExactDate::from_date_perhaps_time(nd).to_naive();
-                        dates.insert(ExactDate::from_naive(end));
+        for record in ics_to_records(cal) {
+            if let ExactRecord::Event(event) = record {
+                for range in event.occurrences() {
+                    let (start, end) = match range {
+                        ExactRange::TimeRange(range) => (range.start.date, range.end.date),
+                        ExactRange::AllDay(range) => (range.start, range.end),
+                    };
+                    let (Ok(start), Ok(end)) = (start.to_chrono(), end.to_chrono()) else {
+                        continue;
+                    };
+                    for date in start.iter_days().take_while(|date| *date <= end) {
+                        dates.insert(ExactDate::from_naive(date));
                     }
-                    (_, _) => {}
                 }
             }
         }
@@ -66,13 +69,12 @@ impl SetFilter {
     }
 }
 
-
 #[cfg(not(target_family = "wasm"))]
 pub fn import_ics(url: &String) -> Result<Calendar>{
     use crate::utils::Dirs;
 
     let contents = if url.starts_with("http"){
-        let loc = get_dir(Dirs::Cache, Some(&"ics".to_string()))?.join(url);
+        let loc = get_dir(Dirs::Cache, Some("ics"))?.join(url);
         download_file(url, loc.clone(), None)?;
         let mut contents = String::new();
         File::open(loc)?.read_to_string(&mut contents)?;
@@ -100,7 +102,16 @@ pub fn ics_to_records(cal: &Calendar) -> Vec<ExactRecord>{
     let mut records = vec![];
     for c in cal.iter(){
         if let Some(event) = c.as_event() {
-            let range  = match (event.get_start(), event.get_end()) {
+            let range = match (event.get_start(), event.get_end()) {
+                // DTEND of an all-day event is exclusive
+                (Some(DatePerhapsTime::Date(st)), Some(DatePerhapsTime::Date(nd))) => {
+                    let start = ExactDate::from_naive(st);
+                    let end = nd.pred_opt().filter(|nd| *nd >= st).unwrap_or(st);
+                    ExactRange::AllDay(ExactDateRange {
+                        start,
+                        end: ExactDate::from_naive(end),
+                    })
+                }
                 (Some(st), Some(nd)) => {
                     let est = ExactDateTime::from_date_perhaps_time(st);
                     let end  = ExactDateTime::from_date_perhaps_time(nd);
@@ -111,19 +122,20 @@ pub fn ics_to_records(cal: &Calendar) -> Vec<ExactRecord>{
                 }
                 (Some(st), None) => {
                     let est = ExactDate::from_date_perhaps_time(st);
-                    ExactRange::AllDay(est)
+                    ExactRange::AllDay(ExactDateRange::single(est))
                 }
                 (None, Some(nd)) => {
                     let end = ExactDate::from_date_perhaps_time(nd);
-                    ExactRange::AllDay(end)
+                    ExactRange::AllDay(ExactDateRange::single(end))
                 }
                 (_, _) => {continue;}
             };
+            let recurrence = ics_recurrence(event, &range).map(Box::new);
             let description = event.get_description().map(|s| s.to_string());
             let properties:Vec<ExactProperty> = event
                 .properties()
-                .iter()
-                .map(|(_,v)|
+                .values()
+                .map(|v|
                     {ExactProperty{name:v.key().to_string(), data:v.value().to_string()}}
                 ).collect();
             records.push(ExactRecord::Event(ExactEvent{
@@ -132,7 +144,10 @@ pub fn ics_to_records(cal: &Calendar) -> Vec<ExactRecord>{
                 notes: Some(ExactNotes{
                     description:description.unwrap_or_default(),
                     properties
-                })})
+                }),
+                span: None,
+                recurrence,
+            })
             )
         }
         if let Some(td) = c.as_todo(){
@@ -140,8 +155,122 @@ pub fn ics_to_records(cal: &Calendar) -> Vec<ExactRecord>{
                 name: td.get_summary().unwrap_or("").to_string(),
                 due: None,
                 status: td.get_status().unwrap_or(icalendar::TodoStatus::NeedsAction),
+                span: None,
             }))
         }
     }
     records
 }
+
+// The date of an UNTIL or EXDATE value, on the clock of the series it belongs to
+fn ics_date(value: &str, tz: TimeZoneChoice) -> Option<ExactDate> {
+    let date = match value.strip_suffix('Z') {
+        Some(utc) => {
+            let utc = NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S").ok()?;
+            match tz {
+                TimeZoneChoice::Named(tz) => tz.from_utc_datetime(&utc).date_naive(),
+                TimeZoneChoice::Offset(offset) => offset.from_utc_datetime(&utc).date_naive(),
+                _ => utc.date(),
+            }
+        }
+        None => NaiveDate::parse_from_str(value.get(..8)?, "%Y%m%d").ok()?,
+    };
+    Some(ExactDate::from_naive(date))
+}
+
+/// The recurrence of an event, if its RRULE is one timeblok writes: a daily, weekly or monthly rule
+/// that ends on a date. Events with other rules keep only their first occurrence.
+fn ics_recurrence(event: &icalendar::Event, range: &ExactRange) -> Option<Recurrence> {
+    let tz = match range {
+        ExactRange::TimeRange(range) => range.start.tz,
+        ExactRange::AllDay(_) => TimeZoneChoice::Local,
+    };
+    let integers = |value: &str| value.split(',').map(|n| n.parse().ok()).collect::<Option<Vec<i64>>>();
+    let weekday = |code: &str| -> Option<(i64, Weekday)> {
+        let split = code.len().checked_sub(2)?;
+        let weekday = match code.get(split..)? {
+            "MO" => Weekday::Mon,
+            "TU" => Weekday::Tue,
+            "WE" => Weekday::Wed,
+            "TH" => Weekday::Thu,
+            "FR" => Weekday::Fri,
+            "SA" => Weekday::Sat,
+            "SU" => Weekday::Sun,
+            _ => return None,
+        };
+        let n = match &code[..split] {
+            "" => 0,
+            n => n.trim_start_matches('+').parse().ok()?,
+        };
+        Some((n, weekday))
+    };
+    let mut rule = RecurrenceRule {
+        freq: Frequency::Daily,
+        interval: 1,
+        by_day: vec![],
+        by_month_day: vec![],
+        by_month: vec![],
+    };
+    let (mut freq, mut until) = (None, None);
+    for part in event.property_value("RRULE")?.split(';') {
+        let (name, value) = part.split_once('=')?;
+        match name.to_uppercase().as_str() {
+            "FREQ" => {
+                freq = Some(match value.to_uppercase().as_str() {
+                    "DAILY" => Frequency::Daily,
+                    "WEEKLY" => Frequency::Weekly,
+                    "MONTHLY" => Frequency::Monthly,
+                    _ => return None,
+                })
+            }
+            "INTERVAL" => rule.interval = value.parse().ok().filter(|n| *n > 0)?,
+            "BYMONTH" => rule.by_month = integers(value)?,
+            "BYMONTHDAY" => rule.by_month_day = integers(value)?,
+            "BYDAY" => rule.by_day = value.split(',').map(|code| weekday(&code.to_uppercase())).collect::<Option<_>>()?,
+            "UNTIL" => until = Some(ics_date(value, tz)?),
+            "WKST" if value.eq_ignore_ascii_case("MO") => {}
+            _ => return None,
+        }
+    }
+    rule.freq = freq?;
+    let exdates = match event.property_value("EXDATE") {
+        Some(values) => values.split(',').map(|value| ics_date(value, tz)).collect::<Option<_>>()?,
+        None => vec![],
+    };
+    Some(Recurrence { rule, until: until?, exdates })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_import_series() -> Result<()> {
+        let ics = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:test\r\n\
+            BEGIN:VEVENT\r\nUID:1\r\nDTSTAMP:20230301T000000Z\r\nSUMMARY:Standup\r\n\
+            DTSTART;TZID=America/New_York:20230306T090000\r\n\
+            DTEND;TZID=America/New_York:20230306T093000\r\n\
+            RRULE:FREQ=WEEKLY;BYDAY=MO;UNTIL=20230327T130000Z\r\n\
+            EXDATE;TZID=America/New_York:20230313T090000,20230320T090000\r\n\
+            END:VEVENT\r\nEND:VCALENDAR\r\n";
+        let cal = Calendar::from_str(ics).map_err(|e| anyhow!(e))?;
+        let records = ics_to_records(&cal);
+        let event = match records.as_slice() {
+            [ExactRecord::Event(event)] => event,
+            records => panic!("expected one event, got {:?}", records),
+        };
+        let starts: Vec<ExactDate> = event.occurrences().iter().map(|range| range.start_date()).collect();
+        assert_eq!(starts, vec![ExactDate::from_ymd(2023, 3, 6), ExactDate::from_ymd(2023, 3, 27)]);
+        match &event.range {
+            ExactRange::TimeRange(range) => {
+                assert_eq!(range.start.tz, TimeZoneChoice::Named(chrono_tz::America::New_York));
+                assert_eq!(range.start.time.hour, 9);
+            }
+            range => panic!("expected a time range, got {:?}", range),
+        }
+        let filter = SetFilter::from_ics(&cal);
+        assert!(filter.dates.contains(&ExactDate::from_ymd(2023, 3, 27)));
+        assert!(!filter.dates.contains(&ExactDate::from_ymd(2023, 3, 13)));
+        Ok(())
+    }
+}
@@ -5,7 +5,7 @@ use anyhow::{anyhow, Result};
 use crate::environment::Environment;
 use crate::resolver::ResolverAction;
 
-use super::{ident::IdentData, Value};
+use super::{ident::IdentData, Span, Value};
 
 pub type CommandRes = Option<Vec<ResolverAction>>;
 pub type CmdFn = Rc<dyn Fn(&Environment, &CommandCall) -> Result<CommandRes>>;
@@ -42,7 +42,8 @@ impl Command {
 pub struct CommandCall {
     pub command: String,
     pub args: Vec<Value>,
-    pub plain: String // Preserve the original argument string for custom parsing/error messages
+    pub plain: String, // Preserve the original argument string for custom parsing/error messages
+    pub span: Span,
 }
 
 impl CommandCall {
@@ -1,38 +1,58 @@
+use std::fmt::{Display, Formatter, Result};
+
 use super::*;
 
-impl ToString for ExactTime {
-    fn to_string(&self) -> String {
-        format!("{:02}:{:02}:{:02}", self.hour, self.minute, self.second)
+impl Display for ExactTime {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{:02}:{:02}:{:02}", self.hour, self.minute, self.second)
+    }
+}
+
+impl Display for ExactDate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
     }
 }
 
-impl ToString for ExactDate {
-    fn to_string(&self) -> String {
-        format!("{:04}-{:02}-{:02}", self.year, self.month, self.day)
+impl Display for ExactDateTime {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{} {}", self.date, self.time)
     }
 }
 
-impl ToString for ExactDateTime {
-    fn to_string(&self) -> String {
-        format!(
-            "{} {}",
-            self.date.to_string(),
-            self.time.to_string()
-        )
+impl Display for ExactTimeRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}-{}", self.start, self.end)
     }
 }
 
-impl ToString for ExactTimeRange{
-    fn to_string(&self) -> String{
-        format!("{}-{}", self.start.to_string(), self.end.to_string())
+impl Display for ExactDateRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        if self.start == self.end {
+            self.start.fmt(f)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
     }
 }
 
-impl ToString for ExactRange{
-    fn to_string(&self) -> String{
+impl Display for ExactRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         match self {
-            ExactRange::AllDay(d) => d.to_string(),
-            ExactRange::TimeRange(d) =>  d.to_string()
+            ExactRange::AllDay(d) => d.fmt(f),
+            ExactRange::TimeRange(d) => d.fmt(f),
         }
     }
-}
\ No newline at end of file
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "line {}, column {}", self.line, self.col)
+    }
+}
+
+impl Display for SpannedError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}: {}", self.span, self.error)
+    }
+}
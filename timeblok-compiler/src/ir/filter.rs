@@ -1,8 +1,14 @@
+use std::collections::{BTreeSet, HashSet};
 use std::fmt::Debug;
+use std::hash::Hash;
 
+use chrono::{Datelike, Days, NaiveDate, Weekday};
 use dyn_clone::DynClone;
 
+use crate::diagnostic::Diagnostic;
 use crate::environment::Environment;
+use crate::ir::ident::{Ident, IdentData};
+use crate::ir::recurrence::DatePattern;
 use crate::ir::NumVal::{Number, Unsure};
 use crate::ir::*;
 use crate::resolver::{resolve_date, resolve_range};
@@ -19,8 +25,23 @@ pub trait Filter<T>: Debug + DynClone {
         }
         res
     }
+    /// The span of dates outside which nothing matches, if the filter is bounded.
+    fn window(&self, _env: Option<&Environment>) -> Option<ExactDateRange> {
+        None
+    }
+    /// The numbers the filter matches, if it only matches a known set of them.
+    fn values(&self) -> Option<BTreeSet<i64>> {
+        None
+    }
+    /// Conditions every matching date meets that a recurrence rule can express, if there are any.
+    fn pattern(&self, _env: Option<&Environment>) -> Option<DatePattern> {
+        None
+    }
 }
 
+// Largest set of numbers a range is expanded to
+const MAX_VALUES: i64 = 366;
+
 dyn_clone::clone_trait_object!(<T> Filter<T>);
 
 #[derive(Debug, Clone)]
@@ -38,13 +59,55 @@ pub struct BinFilt<T: Debug> {
     pub op: Op,
 }
 
-impl<T: Debug + Clone> Filter<T> for BinFilt<T> {
+impl<T: Debug + Clone + Eq + Hash> Filter<T> for BinFilt<T> {
     fn check(&self, value: &T, env: Option<&Environment>) -> bool {
         match self.op {
             Op::OR => self.lhs.check(value, env) || self.rhs.check(value, env),
             Op::And => self.lhs.check(value, env) && self.rhs.check(value, env),
         }
     }
+    fn filter(&self, values: Vec<T>, env: Option<&Environment>) -> Vec<T> {
+        // Both sides see every value, so that a selection on either side is made over all of them
+        let lhs: HashSet<T> = self.lhs.filter(values.clone(), env).into_iter().collect();
+        let rhs: HashSet<T> = self.rhs.filter(values.clone(), env).into_iter().collect();
+        values
+            .into_iter()
+            .filter(|v| match self.op {
+                Op::OR => lhs.contains(v) || rhs.contains(v),
+                Op::And => lhs.contains(v) && rhs.contains(v),
+            })
+            .collect()
+    }
+    fn window(&self, env: Option<&Environment>) -> Option<ExactDateRange> {
+        let (lhs, rhs) = (self.lhs.window(env), self.rhs.window(env));
+        match self.op {
+            Op::OR => Some(lhs?.hull(&rhs?)),
+            Op::And => match (lhs, rhs) {
+                (Some(lhs), Some(rhs)) => lhs.intersect(&rhs),
+                (lhs, rhs) => lhs.or(rhs),
+            },
+        }
+    }
+    fn values(&self) -> Option<BTreeSet<i64>> {
+        let (lhs, rhs) = (self.lhs.values(), self.rhs.values());
+        match self.op {
+            Op::OR => Some(&lhs? | &rhs?),
+            Op::And => match (lhs, rhs) {
+                (Some(lhs), Some(rhs)) => Some(&lhs & &rhs),
+                (lhs, rhs) => lhs.or(rhs),
+            },
+        }
+    }
+    fn pattern(&self, env: Option<&Environment>) -> Option<DatePattern> {
+        let (lhs, rhs) = (self.lhs.pattern(env), self.rhs.pattern(env));
+        match self.op {
+            Op::OR => Some(lhs?.union(&rhs?)),
+            Op::And => match (lhs, rhs) {
+                (Some(lhs), Some(rhs)) => Some(lhs.intersect(&rhs)),
+                (lhs, rhs) => lhs.or(rhs),
+            },
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -58,10 +121,14 @@ impl<T: Debug + Clone> ExcludeFilt<T> {
     }
 }
 
-impl<T: Debug + Clone> Filter<T> for ExcludeFilt<T> {
+impl<T: Debug + Clone + Eq + Hash> Filter<T> for ExcludeFilt<T> {
     fn check(&self, value: &T, env: Option<&Environment>) -> bool {
         !self.target.check(value, env)
     }
+    fn filter(&self, values: Vec<T>, env: Option<&Environment>) -> Vec<T> {
+        let excluded: HashSet<T> = self.target.filter(values.clone(), env).into_iter().collect();
+        values.into_iter().filter(|v| !excluded.contains(v)).collect()
+    }
 }
 
 impl Filter<NumVal> for NumRange {
@@ -76,34 +143,59 @@ impl Filter<NumVal> for NumRange {
             _ => true,
         }
     }
+    fn values(&self) -> Option<BTreeSet<i64>> {
+        match (&self.start, &self.end) {
+            (Number(st), Number(nd)) if nd - st <= MAX_VALUES => Some((*st..=*nd).collect()),
+            _ => None,
+        }
+    }
 }
 
 impl Filter<NumVal> for NumVal {
     fn check(&self, value: &NumVal, _env: Option<&Environment>) -> bool {
         matches!(self, Unsure) || self==value
     }
+    fn values(&self) -> Option<BTreeSet<i64>> {
+        match self {
+            Number(n) => Some(BTreeSet::from([*n])),
+            Unsure => None,
+        }
+    }
 }
 
 impl Filter<ExactDate> for ExactRange {
     fn check(&self, value: &ExactDate, _env: Option<&Environment>) -> bool {
         match self {
             ExactRange::TimeRange(tr) => {
-                // unwrap or return false
-                let start = tr.start.date.to_chrono().unwrap();
-                let end = tr.end.date.to_chrono().unwrap();
-                let target = value.to_chrono().unwrap();
-                target >= start && target <= end
+                match (tr.start.date.to_chrono(), tr.end.date.to_chrono(), value.to_chrono()) {
+                    (Ok(start), Ok(end), Ok(target)) => target >= start && target <= end,
+                    _ => false,
+                }
             }
-            ExactRange::AllDay(date) => date == value,
+            ExactRange::AllDay(range) => range.start <= *value && *value <= range.end,
         }
     }
 }
 
 impl Filter<Date> for Range {
     fn check(&self, value: &Date, env: Option<&Environment>) -> bool {
-        let exact_range = resolve_range(self, env.unwrap()).unwrap();
-        let exact_date = resolve_date(value, env.unwrap()).unwrap();
-        exact_range.check(&exact_date, env)
+        let env = match env {
+            Some(env) => env,
+            None => return false,
+        };
+        match (resolve_range(self, env), resolve_date(value, env)) {
+            (Ok(exact_range), Ok(exact_date)) => exact_range.check(&exact_date, Some(env)),
+            _ => false,
+        }
+    }
+    fn window(&self, env: Option<&Environment>) -> Option<ExactDateRange> {
+        match resolve_range(self, env?).ok()? {
+            ExactRange::TimeRange(tr) => Some(ExactDateRange {
+                start: tr.start.date,
+                end: tr.end.date,
+            }),
+            ExactRange::AllDay(range) => Some(range),
+        }
     }
 }
 
@@ -126,11 +218,364 @@ impl Filter<ExactDate> for FlexDate {
 
 impl Filter<Date> for FlexDate {
     fn check(&self, value: &Date, env: Option<&Environment>) -> bool {
-        let exact_date = resolve_date(value, env.unwrap()).unwrap();
+        let exact_date = match env.map(|env| resolve_date(value, env)) {
+            Some(Ok(d)) => d,
+            _ => return false,
+        };
         self.year.check(&Number(exact_date.year as i64), env)
             && self.month.check(&Number(exact_date.month as i64), env)
             && self.day.check(&Number(exact_date.day as i64), env)
     }
+    fn pattern(&self, _env: Option<&Environment>) -> Option<DatePattern> {
+        let (months, month_days) = (self.month.values(), self.day.values());
+        if months.is_none() && month_days.is_none() {
+            return None;
+        }
+        Some(DatePattern {
+            months,
+            month_days,
+            ..Default::default()
+        })
+    }
+}
+
+/// The nth given weekday of the month, counted from the end of the month when negative.
+/// eg. `{2nd tue}` or `{last fri}`
+#[derive(Debug, Clone)]
+pub struct NthWeekday {
+    pub weekday: Weekday,
+    pub n: i64,
+}
+
+impl Filter<ExactDate> for NthWeekday {
+    fn check(&self, value: &ExactDate, _env: Option<&Environment>) -> bool {
+        let date = match value.to_chrono() {
+            Ok(date) => date,
+            Err(_) => return false,
+        };
+        if date.weekday() != self.weekday {
+            return false;
+        }
+        let nth = if self.n > 0 {
+            (date.day() as i64 - 1) / 7 + 1
+        } else {
+            let (year, month) = match date.month() {
+                12 => (date.year() + 1, 1),
+                m => (date.year(), m + 1),
+            };
+            let days_in_month = match NaiveDate::from_ymd_opt(year, month, 1).and_then(|d| d.pred_opt()) {
+                Some(last) => last.day() as i64,
+                None => return false,
+            };
+            -((days_in_month - date.day() as i64) / 7 + 1)
+        };
+        nth == self.n
+    }
+}
+
+impl Filter<Date> for NthWeekday {
+    fn check(&self, value: &Date, env: Option<&Environment>) -> bool {
+        match env.map(|env| resolve_date(value, env)) {
+            Some(Ok(date)) => self.check(&date, env),
+            _ => false,
+        }
+    }
+    fn pattern(&self, _env: Option<&Environment>) -> Option<DatePattern> {
+        Some(DatePattern {
+            weekdays: Some(vec![(self.n, self.weekday)]),
+            ..Default::default()
+        })
+    }
+}
+
+/// Any of a set of weekdays, eg. `{mon}` or `{workday}`
+#[derive(Debug, Clone)]
+pub struct Weekdays {
+    pub days: Vec<Weekday>,
+}
+
+impl Filter<Date> for Weekdays {
+    fn check(&self, value: &Date, env: Option<&Environment>) -> bool {
+        match env.map(|env| resolve_date(value, env).and_then(|d| d.to_chrono())) {
+            Some(Ok(date)) => self.days.contains(&date.weekday()),
+            _ => false,
+        }
+    }
+    fn pattern(&self, _env: Option<&Environment>) -> Option<DatePattern> {
+        Some(DatePattern {
+            weekdays: Some(self.days.iter().map(|w| (0, *w)).collect()),
+            ..Default::default()
+        })
+    }
+}
+
+/// Every nth day from an anchor date on, eg. `{every 2 weeks from 2023-1-4}`
+#[derive(Debug, Clone)]
+pub struct Interval {
+    pub anchor: Date,
+    pub days: i64,
+}
+
+impl Filter<Date> for Interval {
+    fn check(&self, value: &Date, env: Option<&Environment>) -> bool {
+        let env = match env {
+            Some(env) => env,
+            None => return false,
+        };
+        let resolve = |date: &Date| resolve_date(date, env).and_then(|d| d.to_chrono());
+        match (resolve(&self.anchor), resolve(value)) {
+            (Ok(anchor), Ok(date)) => {
+                let days = (date - anchor).num_days();
+                days >= 0 && days % self.days == 0
+            }
+            _ => false,
+        }
+    }
+    fn pattern(&self, env: Option<&Environment>) -> Option<DatePattern> {
+        let anchor = resolve_date(&self.anchor, env?).and_then(|d| d.to_chrono()).ok()?;
+        Some(DatePattern {
+            every: Some((anchor, self.days)),
+            ..Default::default()
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+    Week,
+    Month,
+    Year,
+}
+
+impl Period {
+    /// The dates of the period that `date` falls in, weeks starting on monday.
+    fn dates_around(&self, date: NaiveDate) -> Vec<NaiveDate> {
+        let same_period = |d: &NaiveDate| match self {
+            Period::Week => d.iso_week() == date.iso_week(),
+            Period::Month => d.month() == date.month() && d.year() == date.year(),
+            Period::Year => d.year() == date.year(),
+        };
+        let start = match self {
+            Period::Week => date.checked_sub_days(Days::new(date.weekday().num_days_from_monday() as u64)),
+            Period::Month => date.with_day(1),
+            Period::Year => date.with_ordinal(1),
+        };
+        match start {
+            Some(start) => start.iter_days().take_while(same_period).collect(),
+            None => vec![],
+        }
+    }
+}
+
+/// The nth date that matches a filter within each period, counted from the end when negative.
+/// Without a period, the dates being filtered are used, or the dates of the current occasion when
+/// a single date is checked. eg. `{workday}[-1] per month`
+#[derive(Debug, Clone)]
+pub struct Select {
+    pub filter: BDF<Date>,
+    pub n: i64,
+    pub period: Option<Period>,
+}
+
+impl Select {
+    fn select(&self, dates: Vec<Date>, env: Option<&Environment>) -> Option<Date> {
+        let matches = self.filter.filter(dates, env);
+        let index = if self.n > 0 {
+            self.n - 1
+        } else {
+            matches.len() as i64 + self.n
+        };
+        usize::try_from(index).ok().and_then(|i| matches.get(i)).copied()
+    }
+}
+
+impl Filter<Date> for Select {
+    fn check(&self, value: &Date, env: Option<&Environment>) -> bool {
+        let env = match env {
+            Some(env) => env,
+            None => return false,
+        };
+        let date = match resolve_date(value, env).and_then(|d| d.to_chrono()) {
+            Ok(date) => date,
+            Err(_) => return false,
+        };
+        let candidates: Vec<Date> = match self.period {
+            Some(period) => period.dates_around(date).into_iter().map(Date::from_naive).collect(),
+            None => env.iter().collect(),
+        };
+        let selected = match self.select(candidates, Some(env)) {
+            Some(selected) => selected,
+            None => return false,
+        };
+        matches!(resolve_date(&selected, env), Ok(d) if d == ExactDate::from_naive(date))
+    }
+    fn filter(&self, values: Vec<Date>, env: Option<&Environment>) -> Vec<Date> {
+        match self.period {
+            Some(_) => values.into_iter().filter(|v| self.check(v, env)).collect(),
+            // The selection is made once over all the dates
+            None => self.select(values, env).into_iter().collect(),
+        }
+    }
+    fn window(&self, env: Option<&Environment>) -> Option<ExactDateRange> {
+        self.filter.window(env)
+    }
+    fn pattern(&self, env: Option<&Environment>) -> Option<DatePattern> {
+        let pattern = self.filter.pattern(env)?;
+        let weekdays_only = pattern == DatePattern { weekdays: pattern.weekdays.clone(), ..Default::default() };
+        let selected = match (pattern.weekdays.as_deref(), self.period) {
+            // The nth of a weekday in each month is an nth weekday
+            (Some(&[(0, weekday)]), Some(Period::Month)) if weekdays_only => (self.n, weekday),
+            // And the nth of some weekdays in each week is one of them
+            (Some(days), Some(Period::Week)) if weekdays_only && days.iter().all(|(n, _)| *n == 0) => {
+                let mut days: Vec<Weekday> = days.iter().map(|(_, w)| *w).collect();
+                days.sort_by_key(|w| w.num_days_from_monday());
+                let index = if self.n > 0 { self.n - 1 } else { days.len() as i64 + self.n };
+                match usize::try_from(index).ok().and_then(|i| days.get(i)) {
+                    Some(weekday) => (0, *weekday),
+                    None => return Some(pattern),
+                }
+            }
+            _ => return Some(pattern),
+        };
+        Some(DatePattern {
+            weekdays: Some(vec![selected]),
+            ..Default::default()
+        })
+    }
+}
+
+/// ISO week numbers, eg. `{W10~W20}`, each date counted in the week-numbering year it belongs to.
+/// So the last days of december can be in week 1, and a window across new year goes on to the weeks of the next year.
+#[derive(Debug, Clone)]
+pub struct IsoWeek {
+    pub weeks: NumRange,
+}
+
+impl Filter<Date> for IsoWeek {
+    fn check(&self, value: &Date, env: Option<&Environment>) -> bool {
+        let env = match env {
+            Some(env) => env,
+            None => return false,
+        };
+        match resolve_date(value, env).and_then(|d| d.to_chrono()) {
+            Ok(date) => self.weeks.check(&Number(date.iso_week().week() as i64), Some(env)),
+            _ => false,
+        }
+    }
+}
+
+/// The weeks of a term set with `/term`, counted from 1 for the week its start date falls in.
+/// Weeks start on monday. eg. `{fall week 3~10}`
+#[derive(Debug, Clone)]
+pub struct TermWeek {
+    pub term: Ident,
+    pub weeks: BDF<NumVal>,
+}
+
+impl Filter<Date> for TermWeek {
+    fn check(&self, value: &Date, env: Option<&Environment>) -> bool {
+        let env = match env {
+            Some(env) => env,
+            None => return false,
+        };
+        let start = match env.get(&self.term.name) {
+            Some(IdentData::Value(Value::Date(start))) => start,
+            _ => {
+                env.report(Diagnostic::warning(
+                    "unknown-term",
+                    format!("{} is not a term, set one with `/term {} [start date]`", self.term.name, self.term.name),
+                ));
+                return false;
+            }
+        };
+        let resolve = |date: &Date| resolve_date(date, env).and_then(|d| d.to_chrono());
+        match (resolve(&start), resolve(value)) {
+            (Ok(start), Ok(date)) => {
+                let monday = start - chrono::Duration::days(start.weekday().num_days_from_monday() as i64);
+                let week = (date - monday).num_days().div_euclid(7) + 1;
+                self.weeks.check(&Number(week), Some(env))
+            }
+            _ => false,
+        }
+    }
+}
+
+// Furthest a shift looks for a match of a target without a window, in days
+const MAX_SHIFT_DAYS: usize = 1000;
+
+/// The dates `n` days after the matches of a filter, or before them when negative.
+/// With a unit, only the days that match it are counted. eg. `{USholiday + 1d}` or `{deadline - 2 workday}`
+#[derive(Debug, Clone)]
+pub struct Shift {
+    pub target: BDF<Date>,
+    pub n: i64,
+    pub unit: Option<BDF<Date>>,
+}
+
+impl Filter<Date> for Shift {
+    fn check(&self, value: &Date, env: Option<&Environment>) -> bool {
+        let mut day = match env.map(|env| resolve_date(value, env).and_then(|d| d.to_chrono())) {
+            Some(Ok(day)) => day,
+            _ => return false,
+        };
+        let counts = |day: NaiveDate| match &self.unit {
+            Some(unit) => unit.check(&Date::from_naive(day), env),
+            None => true,
+        };
+        if !counts(day) {
+            return false;
+        }
+        if self.unit.is_none() {
+            return match day.checked_sub_signed(chrono::Duration::days(self.n)) {
+                Some(target) => self.target.check(&Date::from_naive(target), env),
+                None => false,
+            };
+        }
+        // Walking back past the window of the target, there is no match left to find
+        let window = self.target.window(env);
+        let passed = |day: NaiveDate| match &window {
+            Some(window) if self.n > 0 => ExactDate::from_naive(day) < window.start,
+            Some(window) => ExactDate::from_naive(day) > window.end,
+            None => false,
+        };
+        // Walks back towards the target, a date matches if a target is passed after exactly n units
+        let n = self.n.unsigned_abs();
+        let mut count = 0;
+        for _ in 0..MAX_SHIFT_DAYS {
+            if counts(day) {
+                count += 1;
+            }
+            if count > n {
+                return false;
+            }
+            day = match if self.n > 0 { day.pred_opt() } else { day.succ_opt() } {
+                Some(day) => day,
+                None => return false,
+            };
+            if passed(day) {
+                return false;
+            }
+            if count == n && self.target.check(&Date::from_naive(day), env) {
+                return true;
+            }
+        }
+        false
+    }
+    fn window(&self, env: Option<&Environment>) -> Option<ExactDateRange> {
+        // Only a shift by calendar days moves the window by a known number of days
+        if self.unit.is_some() {
+            return None;
+        }
+        let window = self.target.window(env)?;
+        let shift = |date: ExactDate| {
+            let date = date.to_chrono().ok()?.checked_add_signed(chrono::Duration::days(self.n))?;
+            Some(ExactDate::from_naive(date))
+        };
+        Some(ExactDateRange {
+            start: shift(window.start)?,
+            end: shift(window.end)?,
+        })
+    }
 }
 
 // Add a unit test for filters
@@ -138,8 +583,10 @@ impl Filter<Date> for FlexDate {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ir::ident::DynFilter;
     use crate::ir::Range;
     use crate::ir::TimeRange;
+    use std::rc::Rc;
     #[test]
     fn test_flex_date() {
         let fd = FlexDate {
@@ -242,4 +689,167 @@ mod tests {
         assert!(combfilt.check(&Number(9), None));
         assert!(!combfilt.check(&Number(2), None));
     }
+
+    #[test]
+    fn test_window() {
+        let env = Environment::from_exact(ExactDateTime::from_ymd_hms(2020, 1, 1, 1, 1, 1));
+        let range = |start: u32, end: u32| -> BDF<Date> {
+            Box::new(Range::Time(TimeRange {
+                start: DateTime::from_ymd(2020, 1, start),
+                end: DateTime::from_ymd(2020, 1, end),
+            }))
+        };
+        let window = |start, end| Some(ExactDateRange {
+            start: ExactDate::from_ymd(2020, 1, start),
+            end: ExactDate::from_ymd(2020, 1, end),
+        });
+        let both = |op| BinFilt { lhs: range(3, 10), rhs: range(8, 20), op };
+        assert_eq!(both(Op::And).window(Some(&env)), window(8, 10));
+        assert_eq!(both(Op::OR).window(Some(&env)), window(3, 20));
+        let unbounded = BinFilt {
+            lhs: range(3, 10),
+            rhs: Box::new(NthWeekday { weekday: Weekday::Mon, n: 1 }),
+            op: Op::OR,
+        };
+        assert_eq!(unbounded.window(Some(&env)), None);
+        assert_eq!(ExcludeFilt::new(range(3, 10)).window(Some(&env)), None);
+    }
+
+    #[test]
+    fn test_interval() {
+        let env = Environment::from_exact(ExactDateTime::from_ymd_hms(2023, 1, 1, 1, 1, 1));
+        let every = Interval {
+            anchor: Date::from_ymd(2023, 1, 4),
+            days: 14,
+        };
+        assert!(every.check(&Date::from_ymd(2023, 1, 4), Some(&env)));
+        assert!(every.check(&Date::from_ymd(2023, 2, 1), Some(&env)));
+        assert!(!every.check(&Date::from_ymd(2023, 1, 11), Some(&env)));
+        assert!(!every.check(&Date::from_ymd(2022, 12, 21), Some(&env)));
+        assert!(!every.check(&Date::from_ymd(2023, 1, 4), None));
+    }
+
+    #[test]
+    fn test_select() {
+        let env = Environment::from_exact(ExactDateTime::from_ymd_hms(2020, 1, 1, 1, 1, 1));
+        let select = |n, period| Select {
+            filter: Box::new(Range::Time(TimeRange {
+                start: DateTime::from_ymd(2020, 1, 3),
+                end: DateTime::from_ymd(2020, 1, 10),
+            })),
+            n,
+            period,
+        };
+        let first = select(1, Some(Period::Month));
+        assert!(first.check(&Date::from_ymd(2020, 1, 3), Some(&env)));
+        assert!(!first.check(&Date::from_ymd(2020, 1, 4), Some(&env)));
+        // 2020-1-6 is a monday
+        let weekly = select(1, Some(Period::Week));
+        assert!(weekly.check(&Date::from_ymd(2020, 1, 6), Some(&env)));
+        assert!(!weekly.check(&Date::from_ymd(2020, 1, 7), Some(&env)));
+        // Without a period, the dates of `2020-1-`
+        let month = DateTime {
+            date: Some(Date { day: Unsure, ..Date::from_ymd(2020, 1, 1) }),
+            ..Default::default()
+        };
+        let env = Environment::new(ExactDateTime::from_ymd_hms(2020, 1, 1, 1, 1, 1), month, None);
+        let last = select(-1, None);
+        assert!(last.check(&Date::from_ymd(2020, 1, 10), Some(&env)));
+        assert!(!last.check(&Date::from_ymd(2020, 1, 3), Some(&env)));
+        assert!(!select(9, None).check(&Date::from_ymd(2020, 1, 3), Some(&env)));
+        // Filtering a window selects among its dates
+        let window: Vec<Date> = (1..=5).map(|d| Date::from_ymd(2020, 1, d)).collect();
+        assert_eq!(last.filter(window.clone(), Some(&env)), vec![Date::from_ymd(2020, 1, 5)]);
+        let not_first = ExcludeFilt::new(Box::new(select(1, None)) as BDF<Date>);
+        assert_eq!(not_first.filter(window.clone(), Some(&env)), window[..2].iter().chain(&window[3..]).copied().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_shift() {
+        let env = Environment::from_exact(ExactDateTime::from_ymd_hms(2023, 1, 1, 1, 1, 1));
+        // 2023-1-6 is a friday, followed by a weekend
+        let friday = || -> BDF<Date> { Box::new(Range::AllDay(Date::from_ymd(2023, 1, 6))) };
+        let weekday = || -> BDF<Date> {
+            Box::new(DynFilter {
+                filter: Rc::new(|date: &Date, _| !matches!(date.day, Number(7) | Number(8))),
+                name: "weekday".to_string(),
+            })
+        };
+        let after = Shift { target: friday(), n: 1, unit: None };
+        assert!(after.check(&Date::from_ymd(2023, 1, 7), Some(&env)));
+        assert!(!after.check(&Date::from_ymd(2023, 1, 6), Some(&env)));
+        let workdays = Shift { target: friday(), n: 2, unit: Some(weekday()) };
+        assert!(workdays.check(&Date::from_ymd(2023, 1, 10), Some(&env)));
+        assert!(!workdays.check(&Date::from_ymd(2023, 1, 9), Some(&env)));
+        assert!(!workdays.check(&Date::from_ymd(2023, 1, 8), Some(&env)));
+        let before = Shift { target: friday(), n: -2, unit: Some(weekday()) };
+        assert!(before.check(&Date::from_ymd(2023, 1, 4), Some(&env)));
+        assert!(!before.check(&Date::from_ymd(2023, 1, 5), Some(&env)));
+        // A date before the target can't be after it
+        let rare = Shift { target: friday(), n: 2, unit: Some(Box::new(Range::AllDay(Date::from_ymd(2023, 1, 2)))) };
+        assert!(!rare.check(&Date::from_ymd(2023, 1, 2), Some(&env)));
+
+        let day = ExactDate::from_ymd(2023, 1, 7);
+        assert_eq!(after.window(Some(&env)), Some(ExactDateRange { start: day, end: day }));
+        assert_eq!(workdays.window(Some(&env)), None);
+    }
+
+    #[test]
+    fn test_iso_week() {
+        let env = Environment::from_exact(ExactDateTime::from_ymd_hms(2024, 1, 1, 0, 0, 0));
+        let first = IsoWeek { weeks: NumRange { start: Number(1), end: Number(1) } };
+        assert!(first.check(&Date::from_ymd(2024, 1, 1), Some(&env)));
+        assert!(first.check(&Date::from_ymd(2024, 1, 7), Some(&env)));
+        // In week 1 of 2025
+        assert!(first.check(&Date::from_ymd(2024, 12, 30), Some(&env)));
+        // 2023-1-1 is in the last week of 2022
+        let env = Environment::from_exact(ExactDateTime::from_ymd_hms(2023, 1, 1, 0, 0, 0));
+        let last = IsoWeek { weeks: NumRange { start: Number(52), end: Number(52) } };
+        assert!(last.check(&Date::from_ymd(2023, 1, 1), Some(&env)));
+        assert!(last.check(&Date::from_ymd(2023, 12, 31), Some(&env)));
+        assert!(!last.check(&Date::from_ymd(2023, 1, 2), Some(&env)));
+        // The weeks of the next year, under an occasion in december
+        let env = Environment::from_exact(ExactDateTime::from_ymd_hms(2023, 12, 1, 0, 0, 0));
+        let early = IsoWeek { weeks: NumRange { start: Number(1), end: Number(2) } };
+        assert!(early.check(&Date::from_ymd(2024, 1, 1), Some(&env)));
+        assert!(early.check(&Date::from_ymd(2024, 1, 14), Some(&env)));
+        assert!(!early.check(&Date::from_ymd(2024, 1, 15), Some(&env)));
+        assert!(!early.check(&Date::from_ymd(2023, 12, 31), Some(&env)));
+    }
+
+    #[test]
+    fn test_term_week() {
+        let env = Environment::from_exact(ExactDateTime::from_ymd_hms(2023, 1, 1, 1, 1, 1));
+        // Starts on a wednesday, so the first week starts on 2023-9-4
+        env.set("fall", IdentData::Value(Value::Date(Date::from_ymd(2023, 9, 6)))).unwrap();
+        let week = |start, end| TermWeek {
+            term: Ident { name: "fall".to_string() },
+            weeks: Box::new(NumRange { start, end }),
+        };
+        assert!(week(Number(1), Number(1)).check(&Date::from_ymd(2023, 9, 4), Some(&env)));
+        assert!(week(Number(2), Number(2)).check(&Date::from_ymd(2023, 9, 11), Some(&env)));
+        assert!(!week(Number(2), Number(2)).check(&Date::from_ymd(2023, 9, 10), Some(&env)));
+        assert!(week(Number(3), Unsure).check(&Date::from_ymd(2024, 1, 1), Some(&env)));
+        assert!(!week(Unsure, Number(1)).check(&Date::from_ymd(2023, 9, 3), None));
+        let unknown = TermWeek {
+            term: Ident { name: "spring".to_string() },
+            weeks: Box::new(Number(1)),
+        };
+        assert!(!unknown.check(&Date::from_ymd(2023, 9, 4), Some(&env)));
+        assert_eq!(env.diagnostics.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_nth_weekday() {
+        // 2023-2-14 is the second tuesday of the month, and the third to last
+        let date = ExactDate::from_ymd(2023, 2, 14);
+        let nth = |weekday, n| NthWeekday { weekday, n };
+        assert!(nth(Weekday::Tue, 2).check(&date, None));
+        assert!(nth(Weekday::Tue, -3).check(&date, None));
+        assert!(!nth(Weekday::Tue, 1).check(&date, None));
+        assert!(!nth(Weekday::Tue, -1).check(&date, None));
+        assert!(!nth(Weekday::Wed, 2).check(&date, None));
+        assert!(nth(Weekday::Tue, -1).check(&ExactDate::from_ymd(2023, 2, 28), None));
+        assert!(nth(Weekday::Fri, -1).check(&ExactDate::from_ymd(2023, 12, 29), None));
+    }
 }
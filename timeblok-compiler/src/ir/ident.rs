@@ -1,6 +1,8 @@
+use crate::diagnostic::Diagnostic;
 use crate::environment::Environment;
 use crate::ir::filter::Filter;
-use crate::ir::Date;
+use crate::ir::recurrence::DatePattern;
+use crate::ir::{Date, ExactDateRange};
 
 use std::fmt::Debug;
 use std::rc::Rc;
@@ -43,17 +45,41 @@ pub struct IdentFilter {
 
 impl Filter<Date> for IdentFilter {
     fn check(&self, value: &Date, env: Option<&Environment>) -> bool {
-        match env.unwrap().get(&self.ident.name) {
-            Some(IdentData::Value(Value::DateFilter(filt))) => filt.check(value, env),
+        let env = match env {
+            Some(env) => env,
+            None => return false,
+        };
+        match env.get(&self.ident.name) {
+            Some(IdentData::Value(Value::DateFilter(filt))) => filt.check(value, Some(env)),
             _ => {
-                eprintln!(
-                    "Warning: {} is not a date filter, returning false",
-                    self.ident.name
-                );
+                env.report(Diagnostic::warning(
+                    "unknown-filter",
+                    format!("{} is not a date filter, no dates will match it", self.ident.name),
+                ));
                 false
             }
         }
     }
+    fn filter(&self, values: Vec<Date>, env: Option<&Environment>) -> Vec<Date> {
+        match env.map(|env| (env, env.get(&self.ident.name))) {
+            Some((env, Some(IdentData::Value(Value::DateFilter(filt))))) => filt.filter(values, Some(env)),
+            _ => values.into_iter().filter(|v| self.check(v, env)).collect(),
+        }
+    }
+    fn window(&self, env: Option<&Environment>) -> Option<ExactDateRange> {
+        let env = env?;
+        match env.get(&self.ident.name) {
+            Some(IdentData::Value(Value::DateFilter(filt))) => filt.window(Some(env)),
+            _ => None,
+        }
+    }
+    fn pattern(&self, env: Option<&Environment>) -> Option<DatePattern> {
+        let env = env?;
+        match env.get(&self.ident.name) {
+            Some(IdentData::Value(Value::DateFilter(filt))) => filt.pattern(Some(env)),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -1,14 +1,32 @@
-use crate::ir::filter::{Filter, BDF};
+use crate::ir::filter::BDF;
 use chrono::{Datelike, NaiveDate, NaiveDateTime, NaiveTime, Timelike, Utc};
 use icalendar::{CalendarDateTime, DatePerhapsTime, Component};
 use anyhow::Result;
 
-use self::{command::CommandCall, ident::Ident};
+use self::{command::CommandCall, ident::Ident, recurrence::Recurrence};
 
 pub mod command;
 pub mod filter;
 pub mod ident;
 pub mod displays;
+pub mod recurrence;
+
+/// Location of a node in the source file, as byte offsets plus the 1-based
+/// line and column of its start.
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub col: usize,
+}
+
+/// An error raised while parsing or resolving the node at `span`.
+#[derive(Debug)]
+pub struct SpannedError {
+    pub span: Span,
+    pub error: anyhow::Error,
+}
 
 #[derive(Debug, Eq, PartialEq, Hash, Copy, Clone)]
 pub enum NumVal {
@@ -32,7 +50,7 @@ pub enum Range {
 #[derive(Debug, Clone)]
 pub enum ExactRange {
     TimeRange(ExactTimeRange),
-    AllDay(ExactDate),
+    AllDay(ExactDateRange),
 }
 
 #[derive(Debug)]
@@ -59,11 +77,20 @@ pub struct ExactTimeRange {
     pub end: ExactDateTime,
 }
 
+/// A span of whole days, both ends included
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ExactDateRange {
+    pub start: ExactDate,
+    pub end: ExactDate,
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum TimeZoneChoice {
     Local,
     Utc,
-    Offset(chrono::offset::FixedOffset)
+    Offset(chrono::offset::FixedOffset),
+    // An IANA time zone, converted with the daylight saving rules of each date
+    Named(chrono_tz::Tz),
 }
 
 #[derive(Default, Debug, Clone)]
@@ -98,9 +125,31 @@ pub struct Date {
     pub year: NumVal,
     pub month: NumVal,
     pub day: NumVal,
+    // Set for dates relative to the current one, the fields above are then ignored
+    pub relative: Option<RelativeDate>,
+    // Set for dates within the year, the month and day are then ignored
+    pub year_date: Option<YearDate>,
+}
+
+/// A date given by its place in the year instead of its month and day
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+pub enum YearDate {
+    /// An ISO week, and optionally a day of the week from 1 for monday, eg. `2023-W15` or `2023-W15-3`.
+    /// The year is then the ISO week-numbering year.
+    Week(u32, Option<u32>),
+    /// A day of the year from 1, eg. `2023-100`
+    Ordinal(u32),
 }
 
 #[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+pub enum RelativeDate {
+    /// Shifts the date by a number of days, eg. `tomorrow` or `-1w`
+    Days(i64),
+    /// The first given weekday after the date, eg. `next fri`
+    Next(chrono::Weekday),
+}
+
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, PartialOrd, Ord)]
 pub struct ExactDate {
     pub year: i32,
     pub month: u32,
@@ -122,7 +171,7 @@ pub struct Time {
     pub tod: Option<Tod>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct ExactTime {
     pub hour: u32,
     pub minute: u32,
@@ -135,22 +184,38 @@ pub struct Event {
     pub range: Range,
     pub name: String,
     pub notes: Option<Notes>,
+    pub span: Span,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ExactEvent {
     pub range: ExactRange,
     pub name: String,
     pub notes: Option<ExactNotes>,
+    // None for records that do not come from a blok file, e.g. imported ones
+    pub span: Option<Span>,
+    // Set when the event stands for a series of events on different dates
+    pub recurrence: Option<Box<Recurrence>>,
+}
+
+impl ExactEvent {
+    /// Attaches the span of the event to `error`, if it has one.
+    pub fn wrap_err(&self, error: anyhow::Error) -> anyhow::Error {
+        match self.span {
+            Some(span) => span.wrap(error),
+            None => error,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Property{
     pub name: String,
-    pub data: Value
+    pub data: Value,
+    pub span: Span,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ExactProperty{
     pub name: String,
     pub data: String
@@ -162,7 +227,7 @@ pub struct Notes{
     pub properties: Vec<Property> 
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ExactNotes{
     pub description: String,
     pub properties: Vec<ExactProperty> 
@@ -171,9 +236,9 @@ pub struct ExactNotes{
 #[derive(Debug)]
 pub enum Record {
     Event(Event),
-    Occasion(DateTime),
-    Note(String),
-    FlexOccasion(FlexOccasion),
+    Occasion(DateTime, Span),
+    Note(String, Span),
+    FlexOccasion(FlexOccasion, Span),
     FlexEvents(FlexEvents),
     Command(CommandCall),
 }
@@ -181,19 +246,32 @@ pub enum Record {
 #[derive(Debug)]
 pub enum ExactRecord {
     Event(ExactEvent),
-    Note(String),
+    Note(String, Option<Span>),
     Todo(Todo)
 }
 
-#[derive(Debug)]
-pub enum FlexOccasion {
-    Filter(Box<dyn Filter<Date>>),
+#[derive(Debug, Clone)]
+pub struct FlexOccasion {
+    pub filter: BDF<Date>,
+    // Default time for the events that follow, eg. `{mon or wed} 10am`
+    pub time: Option<Time>,
+    // Dates to iterate over instead of the scope of the current occasion
+    pub window: Option<Window>,
+}
+
+/// An explicit span of dates for a flexible occasion, eg. `from 2023-9-1 until 2024-6-30`,
+/// or `in semester` for the range named `semester`
+#[derive(Debug, Clone)]
+pub enum Window {
+    Between(Date, Date),
+    Named(Ident),
 }
 
 #[derive(Debug)]
 pub struct FlexEvents {
     pub occasion: FlexOccasion,
     pub events: Vec<Event>,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
@@ -206,6 +284,51 @@ pub enum Value {
     String(String),
 }
 
+impl Span {
+    pub fn from_pest(span: pest::Span) -> Self {
+        let (line, col) = span.start_pos().line_col();
+        Span {
+            start: span.start(),
+            end: span.end(),
+            line,
+            col,
+        }
+    }
+
+    /// Attaches this span to `error`, unless it already carries one.
+    pub fn wrap(self, error: anyhow::Error) -> anyhow::Error {
+        if error.is::<SpannedError>() {
+            return error;
+        }
+        anyhow::Error::new(SpannedError { span: self, error })
+    }
+}
+
+impl std::error::Error for SpannedError {}
+
+impl Record {
+    pub fn span(&self) -> Span {
+        match self {
+            Record::Event(event) => event.span,
+            Record::Occasion(_, span) => *span,
+            Record::Note(_, span) => *span,
+            Record::FlexOccasion(_, span) => *span,
+            Record::FlexEvents(flex_events) => flex_events.span,
+            Record::Command(cmd) => cmd.span,
+        }
+    }
+}
+
+impl ExactRecord {
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            ExactRecord::Event(event) => event.span,
+            ExactRecord::Note(_, span) => *span,
+            ExactRecord::Todo(todo) => todo.span,
+        }
+    }
+}
+
 impl DateTime {
     pub fn from_exact(exact: &ExactDateTime) -> Self {
         DateTime {
@@ -213,6 +336,8 @@ impl DateTime {
                 year: NumVal::Number(exact.date.year as i64),
                 month: NumVal::Number(exact.date.month as i64),
                 day: NumVal::Number(exact.date.day as i64),
+                relative: None,
+                year_date: None,
             }),
             time: Some(Time {
                 hour: NumVal::Number(exact.time.hour as i64),
@@ -237,6 +362,8 @@ impl DateTime {
                 year: NumVal::Number(year as i64),
                 month: NumVal::Number(month as i64),
                 day: NumVal::Number(day as i64),
+                relative: None,
+                year_date: None,
             }),
             time: Some(Time {
                 hour: NumVal::Number(hour as i64),
@@ -253,6 +380,8 @@ impl DateTime {
                 year: NumVal::Number(year as i64),
                 month: NumVal::Number(month as i64),
                 day: NumVal::Number(day as i64),
+                relative: None,
+                year_date: None,
             }),
             time: None,
             tz: Some(TimeZoneChoice::Local),
@@ -317,9 +446,11 @@ impl ExactDateTime {
                     tz: TimeZoneChoice::Utc,
                     ..Self::from_naive(x.naive_utc())
                 },
-                CalendarDateTime::WithTimezone {date_time, tzid: _tz} => {
-                    Self::from_naive(date_time)
-                }
+                // Zones that timeblok can't name keep their wall-clock time
+                CalendarDateTime::WithTimezone {date_time, tzid} => Self{
+                    tz: crate::preset::parse_timezone(&tzid).unwrap_or(TimeZoneChoice::Local),
+                    ..Self::from_naive(date_time)
+                },
             }
         }
     }
@@ -356,6 +487,30 @@ impl ExactDate {
     }
 }
 
+impl ExactDateRange {
+    pub fn single(date: ExactDate) -> Self {
+        Self {
+            start: date,
+            end: date,
+        }
+    }
+
+    /// The dates in both ranges, or None if they do not overlap.
+    pub fn intersect(&self, other: &ExactDateRange) -> Option<ExactDateRange> {
+        let start = self.start.max(other.start);
+        let end = self.end.min(other.end);
+        (start <= end).then_some(ExactDateRange { start, end })
+    }
+
+    /// The smallest range that covers both ranges.
+    pub fn hull(&self, other: &ExactDateRange) -> ExactDateRange {
+        ExactDateRange {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+}
+
 impl Date {
     pub fn new() -> Self {
         use NumVal::Unsure;
@@ -363,6 +518,8 @@ impl Date {
             year: Unsure,
             month: Unsure,
             day: Unsure,
+            relative: None,
+            year_date: None,
         }
     }
 
@@ -372,6 +529,8 @@ impl Date {
             year: Number((naive.year()) as i64),
             month: Number((naive.month()) as i64),
             day: Number((naive.day()) as i64),
+            relative: None,
+            year_date: None,
         }
     }
 
@@ -391,6 +550,8 @@ impl Date {
             year: Number(year as i64),
             month: Number(month as i64),
             day: Number(day as i64),
+            relative: None,
+            year_date: None,
         }
     }
 }
@@ -399,7 +560,8 @@ impl Date {
 pub struct Todo{
     pub name: String,
     pub due: Option<ExactDate>,
-    pub status: icalendar::TodoStatus
+    pub status: icalendar::TodoStatus,
+    pub span: Option<Span>,
 }
 
 impl Todo{
@@ -419,11 +581,20 @@ impl Todo{
         Ok(tod)
     }
 
+    /// Attaches the span of the todo to `error`, if it has one.
+    pub fn wrap_err(&self, error: anyhow::Error) -> anyhow::Error {
+        match self.span {
+            Some(span) => span.wrap(error),
+            None => error,
+        }
+    }
+
     pub fn from_string(s: String) -> Result<Self>{
         Ok(Self{
             name: s,
             due: None,
-            status: icalendar::TodoStatus::NeedsAction
+            status: icalendar::TodoStatus::NeedsAction,
+            span: None,
         })
     }
 }
@@ -0,0 +1,336 @@
+use std::collections::BTreeSet;
+
+use chrono::{Datelike, NaiveDate, Weekday};
+
+use crate::ir::filter::{Filter, NthWeekday};
+use crate::ir::{ExactDate, ExactDateRange, ExactEvent, ExactRange};
+
+/// How often a recurrence rule repeats, as in RFC 5545
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+/// The dates an event repeats on, as a subset of RFC 5545 recurrence rules.
+/// Empty parts place no condition on the dates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecurrenceRule {
+    pub freq: Frequency,
+    pub interval: i64,
+    /// Weekdays, either every one of them when `n` is 0 or the nth of the month
+    pub by_day: Vec<(i64, Weekday)>,
+    pub by_month_day: Vec<i64>,
+    pub by_month: Vec<i64>,
+}
+
+/// A recurrence that starts at the date of its event, and lasts until `until`, both included.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recurrence {
+    pub rule: RecurrenceRule,
+    pub until: ExactDate,
+    pub exdates: Vec<ExactDate>,
+}
+
+/// Conditions that every date matched by a filter meets, each one a part of a recurrence rule.
+/// A missing condition lets any date through, so a pattern may match more dates than its filter.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DatePattern {
+    /// Weekdays, either every one of them when `n` is 0 or the nth of the month
+    pub weekdays: Option<Vec<(i64, Weekday)>>,
+    pub month_days: Option<BTreeSet<i64>>,
+    pub months: Option<BTreeSet<i64>>,
+    /// Every nth day from an anchor date
+    pub every: Option<(NaiveDate, i64)>,
+}
+
+impl DatePattern {
+    /// The dates that meet both patterns.
+    pub fn intersect(&self, other: &DatePattern) -> DatePattern {
+        fn both<T: Clone>(a: &Option<T>, b: &Option<T>, f: impl Fn(&T, &T) -> T) -> Option<T> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(f(a, b)),
+                (a, b) => a.clone().or_else(|| b.clone()),
+            }
+        }
+        DatePattern {
+            weekdays: both(&self.weekdays, &other.weekdays, |a, b| {
+                let mut res = vec![];
+                for &(n, weekday) in a {
+                    for &(m, _) in b.iter().filter(|(_, w)| *w == weekday) {
+                        // An nth weekday narrows down a plain one, while of two different nth
+                        // weekdays the left one is kept, which still matches every date of both
+                        let day = if n == 0 { (m, weekday) } else { (n, weekday) };
+                        if !res.contains(&day) {
+                            res.push(day);
+                        }
+                    }
+                }
+                res
+            }),
+            month_days: both(&self.month_days, &other.month_days, |a, b| a & b),
+            months: both(&self.months, &other.months, |a, b| a & b),
+            every: both(&self.every, &other.every, |a, _| *a),
+        }
+    }
+
+    /// The dates that meet either pattern, as far as a single pattern can describe them.
+    pub fn union(&self, other: &DatePattern) -> DatePattern {
+        fn either<T: Clone>(a: &Option<T>, b: &Option<T>, f: impl Fn(&T, &T) -> Option<T>) -> Option<T> {
+            f(a.as_ref()?, b.as_ref()?)
+        }
+        DatePattern {
+            weekdays: either(&self.weekdays, &other.weekdays, |a, b| {
+                let mut res = a.clone();
+                res.extend(b.iter().filter(|d| !a.contains(d)));
+                Some(res)
+            }),
+            month_days: either(&self.month_days, &other.month_days, |a, b| Some(a | b)),
+            months: either(&self.months, &other.months, |a, b| Some(a | b)),
+            every: either(&self.every, &other.every, |a, b| (a == b).then_some(*a)),
+        }
+    }
+
+    /// The simplest recurrence rule that matches every date of the pattern, if the pattern
+    /// sets any condition.
+    pub fn rule(&self) -> Option<RecurrenceRule> {
+        let sorted = |set: &Option<BTreeSet<i64>>| set.iter().flatten().copied().collect::<Vec<_>>();
+        let mut by_day = self.weekdays.clone().unwrap_or_default();
+        by_day.sort_by_key(|(n, w)| (w.num_days_from_monday(), *n));
+        let (by_month_day, by_month) = (sorted(&self.month_days), sorted(&self.months));
+        if let Some((_, days)) = self.every {
+            // The nth weekday of a month has no place in a daily rule
+            if by_day.iter().any(|(n, _)| *n != 0) {
+                by_day.clear();
+            }
+            if days % 7 == 0 && by_day.is_empty() && by_month_day.is_empty() && by_month.is_empty() {
+                return Some(RecurrenceRule { freq: Frequency::Weekly, interval: days / 7, by_day, by_month_day, by_month });
+            }
+            return Some(RecurrenceRule { freq: Frequency::Daily, interval: days, by_day, by_month_day, by_month });
+        }
+        let freq = if self.month_days.is_some() || by_day.iter().any(|(n, _)| *n != 0) {
+            Frequency::Monthly
+        } else if self.weekdays.is_some() {
+            Frequency::Weekly
+        } else if self.months.is_some() {
+            Frequency::Daily
+        } else {
+            return None;
+        };
+        Some(RecurrenceRule { freq, interval: 1, by_day, by_month_day, by_month })
+    }
+}
+
+impl RecurrenceRule {
+    fn matches(&self, date: NaiveDate, start: NaiveDate) -> bool {
+        let monday = |d: NaiveDate| d - chrono::Duration::days(d.weekday().num_days_from_monday() as i64);
+        let step = match self.freq {
+            Frequency::Daily => (date - start).num_days(),
+            Frequency::Weekly => (monday(date) - monday(start)).num_days() / 7,
+            Frequency::Monthly => (date.year() - start.year()) as i64 * 12 + date.month() as i64 - start.month() as i64,
+        };
+        let weekday = |&(n, weekday): &(i64, Weekday)| match n {
+            0 => date.weekday() == weekday,
+            n => NthWeekday { weekday, n }.check(&ExactDate::from_naive(date), None),
+        };
+        let day = match self.freq {
+            // Without a day of the week or month, a rule repeats the day it starts on
+            Frequency::Weekly if self.by_day.is_empty() => date.weekday() == start.weekday(),
+            Frequency::Monthly if self.by_day.is_empty() && self.by_month_day.is_empty() => date.day() == start.day(),
+            _ => {
+                (self.by_day.is_empty() || self.by_day.iter().any(weekday))
+                    && (self.by_month_day.is_empty() || self.by_month_day.contains(&(date.day() as i64)))
+            }
+        };
+        date >= start
+            && step % self.interval == 0
+            && day
+            && (self.by_month.is_empty() || self.by_month.contains(&(date.month() as i64)))
+    }
+}
+
+impl Recurrence {
+    /// The recurrence of `rule` over `dates`, with the dates between them that the rule also
+    /// matches as exceptions. Returns None if the rule misses one of the dates, or if it has more
+    /// exceptions than dates, in which case listing the dates one by one reads better.
+    pub fn new(rule: RecurrenceRule, dates: &[ExactDate]) -> Option<Recurrence> {
+        let dates: BTreeSet<NaiveDate> = dates
+            .iter()
+            .map(|d| d.to_chrono().ok())
+            .collect::<Option<_>>()?;
+        if dates.len() < 2 {
+            return None;
+        }
+        let start = *dates.iter().next()?;
+        let until = *dates.iter().next_back()?;
+        if !dates.iter().all(|d| rule.matches(*d, start)) {
+            return None;
+        }
+        let exdates: Vec<NaiveDate> = start
+            .iter_days()
+            .take_while(|d| *d <= until)
+            .filter(|d| rule.matches(*d, start) && !dates.contains(d))
+            .collect();
+        if exdates.len() >= dates.len() {
+            return None;
+        }
+        Some(Recurrence {
+            rule,
+            until: ExactDate::from_naive(until),
+            exdates: exdates.into_iter().map(ExactDate::from_naive).collect(),
+        })
+    }
+
+    /// All dates of the recurrence, given the date it starts on.
+    pub fn dates(&self, start: ExactDate) -> Vec<ExactDate> {
+        let (start, until) = match (start.to_chrono(), self.until.to_chrono()) {
+            (Ok(start), Ok(until)) => (start, until),
+            _ => return vec![],
+        };
+        start
+            .iter_days()
+            .take_while(|d| *d <= until)
+            .filter(|d| self.rule.matches(*d, start))
+            .map(ExactDate::from_naive)
+            .filter(|d| !self.exdates.contains(d))
+            .collect()
+    }
+}
+
+impl ExactRange {
+    pub fn start_date(&self) -> ExactDate {
+        match self {
+            ExactRange::TimeRange(tr) => tr.start.date,
+            ExactRange::AllDay(range) => range.start,
+        }
+    }
+
+    /// The same range, moved by a number of days.
+    pub fn shift_days(&self, days: i64) -> Option<ExactRange> {
+        let shift = |date: ExactDate| -> Option<ExactDate> {
+            let date = date.to_chrono().ok()?;
+            let shifted = if days >= 0 {
+                date.checked_add_days(chrono::Days::new(days as u64))
+            } else {
+                date.checked_sub_days(chrono::Days::new(days.unsigned_abs()))
+            };
+            shifted.map(ExactDate::from_naive)
+        };
+        Some(match self {
+            ExactRange::TimeRange(tr) => {
+                let mut res = tr.clone();
+                res.start.date = shift(tr.start.date)?;
+                res.end.date = shift(tr.end.date)?;
+                ExactRange::TimeRange(res)
+            }
+            ExactRange::AllDay(range) => ExactRange::AllDay(ExactDateRange {
+                start: shift(range.start)?,
+                end: shift(range.end)?,
+            }),
+        })
+    }
+}
+
+impl ExactEvent {
+    /// The range of every event in the series, or just the range of the event if it does not recur.
+    pub fn occurrences(&self) -> Vec<ExactRange> {
+        let recurrence = match &self.recurrence {
+            Some(recurrence) => recurrence,
+            None => return vec![self.range.clone()],
+        };
+        let start = self.range.start_date();
+        let first = match start.to_chrono() {
+            Ok(first) => first,
+            Err(_) => return vec![],
+        };
+        recurrence
+            .dates(start)
+            .into_iter()
+            .filter_map(|date| {
+                let days = (date.to_chrono().ok()? - first).num_days();
+                self.range.shift_days(days)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dates(ymds: &[(i32, u32, u32)]) -> Vec<ExactDate> {
+        ymds.iter().map(|&(y, m, d)| ExactDate::from_ymd(y, m, d)).collect()
+    }
+
+    fn weekdays(days: &[(i64, Weekday)]) -> DatePattern {
+        DatePattern {
+            weekdays: Some(days.to_vec()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_rule() {
+        // {tue or thu}
+        let rule = weekdays(&[(0, Weekday::Tue)]).union(&weekdays(&[(0, Weekday::Thu)])).rule().unwrap();
+        assert_eq!(rule.freq, Frequency::Weekly);
+        assert_eq!(rule.by_day, vec![(0, Weekday::Tue), (0, Weekday::Thu)]);
+
+        // {--1~--10 and workday}
+        let days = DatePattern {
+            month_days: Some((1..=10).collect()),
+            ..Default::default()
+        };
+        let workday: Vec<(i64, Weekday)> = [Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri]
+            .iter()
+            .map(|w| (0, *w))
+            .collect();
+        let rule = days.intersect(&weekdays(&workday)).rule().unwrap();
+        assert_eq!(rule.freq, Frequency::Monthly);
+        assert_eq!(rule.by_month_day, (1..=10).collect::<Vec<_>>());
+        assert_eq!(rule.by_day, workday);
+
+        // {2nd tue and tue}
+        let rule = weekdays(&[(2, Weekday::Tue)]).intersect(&weekdays(&[(0, Weekday::Tue)])).rule().unwrap();
+        assert_eq!(rule.by_day, vec![(2, Weekday::Tue)]);
+
+        // {every 2 weeks from 2023-1-4}
+        let every = DatePattern {
+            every: Some((NaiveDate::from_ymd_opt(2023, 1, 4).unwrap(), 14)),
+            ..Default::default()
+        };
+        let rule = every.rule().unwrap();
+        assert_eq!((rule.freq, rule.interval), (Frequency::Weekly, 2));
+
+        // {mon or --1} can't be told apart from any date
+        let first = DatePattern {
+            month_days: Some(BTreeSet::from([1])),
+            ..Default::default()
+        };
+        assert_eq!(weekdays(&[(0, Weekday::Mon)]).union(&first).rule(), None);
+        assert_eq!(DatePattern::default().rule(), None);
+    }
+
+    #[test]
+    fn test_new() {
+        let mondays = weekdays(&[(0, Weekday::Mon)]).rule().unwrap();
+        let days = dates(&[(2023, 1, 2), (2023, 1, 16), (2023, 1, 23), (2023, 1, 30)]);
+        let rec = Recurrence::new(mondays.clone(), &days).unwrap();
+        assert_eq!(rec.exdates, dates(&[(2023, 1, 9)]));
+        assert_eq!(rec.until, ExactDate::from_ymd(2023, 1, 30));
+        assert_eq!(rec.dates(days[0]), days);
+
+        // A rule that misses a date, or has more exceptions than dates, is not used
+        assert_eq!(Recurrence::new(mondays.clone(), &dates(&[(2023, 1, 2), (2023, 1, 3)])), None);
+        assert_eq!(Recurrence::new(mondays.clone(), &dates(&[(2023, 1, 2), (2023, 1, 30)])), None);
+        assert_eq!(Recurrence::new(mondays, &dates(&[(2023, 1, 2)])), None);
+
+        // Second tuesdays
+        let rule = weekdays(&[(2, Weekday::Tue)]).rule().unwrap();
+        let days = dates(&[(2023, 1, 10), (2023, 2, 14), (2023, 3, 14)]);
+        let rec = Recurrence::new(rule, &days).unwrap();
+        assert!(rec.exdates.is_empty());
+        assert_eq!(rec.dates(days[0]), days);
+    }
+}
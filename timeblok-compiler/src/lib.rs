@@ -1,17 +1,16 @@
 
 
-use anyhow::{Result, anyhow};
+use anyhow::Result;
 
 extern crate pest;
-#[macro_use]
 extern crate pest_derive;
 extern crate core;
 
 mod converter;
+pub mod diagnostic;
 mod environment;
 pub mod importer;
 pub mod ir;
-mod output;
 mod parser;
 mod preset;
 mod resolver;
@@ -19,49 +18,97 @@ mod utils;
 #[cfg(test)]
 mod tests;
 
+use diagnostic::Diagnostic;
 use ir::ExactDateTime;
-use parser::{BlokParser, Rule};
-use pest::Parser;
+pub use converter::{AgendaStyle, CsvOptions, IcsTimes, CSV_COLUMNS, JSON_SCHEMA_VERSION};
+pub use resolver::ResolveOptions;
 
-pub fn tb_to_records(tb: &String) -> Result<Vec<ir::Record>> {
-    if let Some(parsed) = BlokParser::parse(Rule::FILE, tb)?.next(){
-        let records = parser::parse_file(parsed)?;
-        Ok(records)
-    }else{
-        Err(anyhow!("Could not parse file"))
-    }
+/// Parses a blok file into records.
+/// Lines that cannot be parsed are skipped and reported in the returned diagnostics.
+pub fn tb_to_records(tb: &str) -> Result<(Vec<ir::Record>, Vec<Diagnostic>)> {
+    Ok(parser::parse_source(tb))
 }
 
-pub fn records_to_resolved(records: Vec<ir::Record>, base_time: ExactDateTime) -> Result<Vec<ir::ExactRecord>> {
-    let resolved = resolver::resolve(records, base_time);
+pub fn records_to_resolved(records: Vec<ir::Record>, base_time: ExactDateTime) -> Result<(Vec<ir::ExactRecord>, Vec<Diagnostic>)> {
+    records_to_resolved_with_options(records, base_time, &ResolveOptions::default())
+}
+
+pub fn records_to_resolved_with_options(records: Vec<ir::Record>, base_time: ExactDateTime, options: &ResolveOptions) -> Result<(Vec<ir::ExactRecord>, Vec<Diagnostic>)> {
+    let resolved = resolver::resolve(records, base_time, options);
     Ok(resolved)
 }
 
-pub fn resolved_to_ical(resolved: Vec<ir::ExactRecord>) -> Result<String> {
-    let ical = converter::to_ical(resolved, None);
+pub fn resolved_to_ical(resolved: Vec<ir::ExactRecord>) -> Result<(String, Vec<Diagnostic>)> {
+    resolved_to_ical_with_times(resolved, IcsTimes::default())
+}
+
+pub fn resolved_to_ical_with_times(resolved: Vec<ir::ExactRecord>, times: IcsTimes) -> Result<(String, Vec<Diagnostic>)> {
+    let ical = converter::to_ical(resolved, None, times);
     Ok(ical)
 }
 
-pub fn resolved_to_ical_deterministic(resolved: Vec<ir::ExactRecord>, base_time: ExactDateTime) -> Result<String> {
-    let ical = converter::to_ical(resolved, Some(base_time.to_chrono()?));
+pub fn resolved_to_ical_deterministic(resolved: Vec<ir::ExactRecord>, base_time: ExactDateTime) -> Result<(String, Vec<Diagnostic>)> {
+    let ical = converter::to_ical(resolved, Some(base_time.to_chrono()?), IcsTimes::default());
     Ok(ical)
 }
 
-pub fn resolved_to_csv(resolved: Vec<ir::ExactRecord>) -> Result<String> {
-    let csv = converter::to_csv(resolved)?;
-    Ok(csv)
+/// Writes resolved records as jCal (RFC 7265), with the same content as the ics output.
+pub fn resolved_to_jcal(resolved: Vec<ir::ExactRecord>, times: IcsTimes) -> Result<(String, Vec<Diagnostic>)> {
+    converter::to_jcal(resolved, None, times)
 }
 
-pub fn compile(source: &str, base_time: ExactDateTime) -> Result<String> {
-    let records = tb_to_records(&source.to_string())?;
-    let resolved = records_to_resolved(records, base_time)?;
-    let ical = resolved_to_ical(resolved)?;
-    Ok(ical)
+/// Writes resolved records as xCal (RFC 6321), with the same content as the ics output.
+pub fn resolved_to_xcal(resolved: Vec<ir::ExactRecord>, times: IcsTimes) -> Result<(String, Vec<Diagnostic>)> {
+    converter::to_xcal(resolved, None, times)
 }
 
-pub fn compile_deterministic(source: &str, base_time: ExactDateTime) -> Result<String> {
-    let records = tb_to_records(&source.to_string())?;
-    let resolved = records_to_resolved(records, base_time.clone())?;
-    let ical = resolved_to_ical_deterministic(resolved, base_time)?;
-    Ok(ical)
+/// Writes resolved records as JSON, in the schema versioned by `JSON_SCHEMA_VERSION`.
+pub fn resolved_to_json(resolved: Vec<ir::ExactRecord>) -> Result<(String, Vec<Diagnostic>)> {
+    converter::to_json(resolved)
+}
+
+pub fn resolved_to_csv(resolved: Vec<ir::ExactRecord>) -> Result<(String, Vec<Diagnostic>)> {
+    resolved_to_csv_with_options(resolved, &CsvOptions::default())
+}
+
+pub fn resolved_to_csv_with_options(resolved: Vec<ir::ExactRecord>, options: &CsvOptions) -> Result<(String, Vec<Diagnostic>)> {
+    converter::to_csv(resolved, options)
+}
+
+/// Writes resolved records as an agenda, listed by day.
+pub fn resolved_to_agenda(resolved: Vec<ir::ExactRecord>, style: AgendaStyle) -> Result<(String, Vec<Diagnostic>)> {
+    converter::to_agenda(resolved, style)
+}
+
+/// Writes resolved records as Org headings, eg. for `org-agenda`.
+pub fn resolved_to_org(resolved: Vec<ir::ExactRecord>) -> Result<(String, Vec<Diagnostic>)> {
+    converter::to_org(resolved)
+}
+
+/// Compiles a blok file to ics.
+/// Problems that did not stop compilation are returned alongside the output.
+pub fn compile(source: &str, base_time: ExactDateTime) -> Result<(String, Vec<Diagnostic>)> {
+    compile_with_options(source, base_time, &ResolveOptions::default())
+}
+
+pub fn compile_with_options(source: &str, base_time: ExactDateTime, options: &ResolveOptions) -> Result<(String, Vec<Diagnostic>)> {
+    let (records, mut diagnostics) = tb_to_records(source)?;
+    let (resolved, resolve_diagnostics) = records_to_resolved_with_options(records, base_time, options)?;
+    diagnostics.extend(resolve_diagnostics);
+    let (ical, convert_diagnostics) = resolved_to_ical(resolved)?;
+    diagnostics.extend(convert_diagnostics);
+    Ok((ical, diagnostics))
+}
+
+pub fn compile_deterministic(source: &str, base_time: ExactDateTime) -> Result<(String, Vec<Diagnostic>)> {
+    compile_deterministic_with_options(source, base_time, &ResolveOptions::default())
+}
+
+pub fn compile_deterministic_with_options(source: &str, base_time: ExactDateTime, options: &ResolveOptions) -> Result<(String, Vec<Diagnostic>)> {
+    let (records, mut diagnostics) = tb_to_records(source)?;
+    let (resolved, resolve_diagnostics) = records_to_resolved_with_options(records, base_time.clone(), options)?;
+    diagnostics.extend(resolve_diagnostics);
+    let (ical, convert_diagnostics) = resolved_to_ical_deterministic(resolved, base_time)?;
+    diagnostics.extend(convert_diagnostics);
+    Ok((ical, diagnostics))
 }
\ No newline at end of file
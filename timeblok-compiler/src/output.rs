@@ -1,5 +0,0 @@
-use std::fmt::Display;
-
-pub fn warn<T: Display>(msg: T) {
-    eprintln!("[Warning] {}", msg);
-}
@@ -1,17 +1,25 @@
 use std::fmt::Debug;
+use std::rc::Rc;
 
+use crate::diagnostic::Diagnostic;
 use crate::ir::command::CommandCall;
 use crate::ir::filter;
 use crate::ir::filter::BinFilt;
 use crate::ir::filter::ExcludeFilt;
+use crate::ir::filter::Interval;
+use crate::ir::filter::NthWeekday;
+use crate::ir::filter::{IsoWeek, Period, Select, Shift, TermWeek};
 use crate::ir::filter::BDF;
-use crate::ir::ident::{Ident, IdentFilter};
+use crate::ir::ident::{DynFilter, Ident, IdentFilter};
 use crate::ir::Range::AllDay;
 use crate::ir::*;
+use crate::preset::parse_timezone;
 use anyhow::anyhow;
 use anyhow::Result;
 use lazy_static::lazy_static;
+use pest::error::{InputLocation, LineColLocation};
 use pest::iterators::{Pair, Pairs};
+use pest::Parser;
 use pest::pratt_parser::PrattParser;
 use pest_derive::Parser;
 
@@ -34,52 +42,143 @@ macro_rules! get_match {
     };
 }
 
-pub fn parse_file(pair: Pair<Rule>) -> Result<Vec<Record>> {
+/// Parses a whole blok file, recovering from errors.
+/// A line that does not fit the grammar is reported and blanked out before parsing again,
+/// so that the records around it are still parsed and the spans stay correct.
+pub fn parse_source(source: &str) -> (Vec<Record>, Vec<Diagnostic>) {
+    let mut source = source.to_string();
+    let mut diagnostics = vec![];
+    loop {
+        match BlokParser::parse(Rule::FILE, &source) {
+            Ok(mut pairs) => {
+                if let Some(file) = pairs.next() {
+                    let (records, record_diagnostics) = parse_file(file);
+                    diagnostics.extend(record_diagnostics);
+                    return (records, diagnostics);
+                }
+                return (vec![], diagnostics);
+            }
+            Err(e) => {
+                let start = match e.location {
+                    InputLocation::Pos(p) => p,
+                    InputLocation::Span((s, _)) => s,
+                };
+                let (line, col) = match e.line_col {
+                    LineColLocation::Pos(p) => p,
+                    LineColLocation::Span(p, _) => p,
+                };
+                let line_end = source[start..].find('\n').map_or(source.len(), |i| start + i);
+                let span = Span { start, end: line_end, line, col };
+                diagnostics.push(Diagnostic::error("syntax", e.variant.message()).with_span(span));
+                // Blank out the offending line, or the closest one above it that has content
+                let mut line_end = line_end;
+                loop {
+                    let line_start = source[..line_end].rfind('\n').map_or(0, |i| i + 1);
+                    if !source[line_start..line_end].trim().is_empty() {
+                        let blank = " ".repeat(line_end - line_start);
+                        source.replace_range(line_start..line_end, &blank);
+                        break;
+                    }
+                    if line_start == 0 {
+                        // Nothing left to blank out
+                        return (vec![], diagnostics);
+                    }
+                    line_end = line_start - 1;
+                }
+            }
+        }
+    }
+}
+
+pub fn parse_file(pair: Pair<Rule>) -> (Vec<Record>, Vec<Diagnostic>) {
     let mut records = vec![];
+    let mut diagnostics = vec![];
     for record in pair.into_inner() {
         match record.as_rule() {
-            Rule::RECORD => records.push(parse_record(record)?),
-            _ => {
-                return Err(anyhow!("Invalid record"));
+            Rule::RECORD => {
+                let span = Span::from_pest(record.as_span());
+                match parse_record(record, &mut diagnostics) {
+                    Ok(record) => records.push(record),
+                    Err(e) => diagnostics.push(Diagnostic::from_error("parse", &span.wrap(e))),
+                }
+            }
+            Rule::EOI => {}
+            r => {
+                let span = Span::from_pest(record.as_span());
+                diagnostics.push(Diagnostic::error("parse", format!("Invalid record: {:?}", r)).with_span(span));
             }
         }
     }
-    Ok(records)
+    (records, diagnostics)
 }
 
-pub fn parse_record(pair: Pair<Rule>) -> Result<Record> {
+pub fn parse_record(pair: Pair<Rule>, diagnostics: &mut Vec<Diagnostic>) -> Result<Record> {
+    let span = Span::from_pest(pair.as_span());
     let mut pairs = pair.into_inner();
     let record = get_next!(pairs);
     match record.as_rule() {
         Rule::EVENT => {
-            let event = parse_event(record)?;
+            let event = parse_event(record, diagnostics)?;
             Ok(Record::Event(event))
         }
         Rule::OCCASION => {
             let occasion = parse_occasion(record)?;
-            Ok(Record::Occasion(occasion))
+            Ok(Record::Occasion(occasion, span))
         }
-        Rule::NOTE_LINE => {
+        Rule::NOTE_LINE | Rule::NOTE => {
             let note = parse_note(record);
-            Ok(Record::Note(note.to_string()))
+            Ok(Record::Note(note.to_string(), span))
         }
         Rule::FLEX_OCCASION => {
             let occasion = parse_flex_occasion(record)?;
-            Ok(Record::FlexOccasion(occasion))
+            Ok(Record::FlexOccasion(occasion, span))
         }
         Rule::FLEX_EVENTS => {
-            let flex_events = parse_flex_events(record)?;
-            Ok(Record::FlexEvents(flex_events))
+            let flex_events = parse_flex_events(record, diagnostics)?;
+            if flex_events.events.is_empty() {
+                // A flexible occasion on its own opens a scope, see `resolver::resolve`
+                Ok(Record::FlexOccasion(flex_events.occasion, span))
+            } else {
+                Ok(Record::FlexEvents(flex_events))
+            }
         }
         Rule::COMMAND => {
             let command = parse_command(record)?;
             Ok(Record::Command(command))
         }
+        Rule::MALFORMED => Err(explain_malformed(record)),
         _ => Err(anyhow!(format!("Invalid record: {:?}", record))),
     }
 }
 
-fn parse_flex_events(pair: Pair<Rule>) -> Result<FlexEvents> {
+/// Re-parses a malformed line as what it looks like, to find out what went wrong.
+fn explain_malformed(pair: Pair<Rule>) -> anyhow::Error {
+    let span = Span::from_pest(pair.as_span());
+    let text = pair.as_str();
+    let (rule, kind) = if text.starts_with('/') {
+        (Rule::COMMAND, "command")
+    } else {
+        (Rule::FLEX_OCCASION, "filter")
+    };
+    match BlokParser::parse(rule, text) {
+        Err(e) => {
+            let offset = match e.location {
+                InputLocation::Pos(p) => p,
+                InputLocation::Span((s, _)) => s,
+            };
+            let at = Span {
+                start: span.start + offset,
+                col: span.col + text[..offset].chars().count(),
+                ..span
+            };
+            at.wrap(anyhow!("Malformed {}: {}", kind, e.variant.message()))
+        }
+        Ok(_) => span.wrap(anyhow!("Malformed {}", kind)),
+    }
+}
+
+fn parse_flex_events(pair: Pair<Rule>, diagnostics: &mut Vec<Diagnostic>) -> Result<FlexEvents> {
+    let span = Span::from_pest(pair.as_span());
     let mut pairs = pair.into_inner();
     let condition = get_match!(parse_flex_occasion, pairs)?;
     let mut events = vec![];
@@ -87,19 +186,25 @@ fn parse_flex_events(pair: Pair<Rule>) -> Result<FlexEvents> {
         let nxt = get_next!(pairs);
         match nxt.as_rule() {
             Rule::EVENT => {
-                let event = parse_event(nxt)?;
-                events.push(event);
+                // A bad event only drops itself, not the rest of the group
+                let span = Span::from_pest(nxt.as_span());
+                match parse_event(nxt, diagnostics) {
+                    Ok(event) => events.push(event),
+                    Err(e) => diagnostics.push(Diagnostic::from_error("parse", &span.wrap(e))),
+                }
             }
-            _ => unreachable!("Invalid rule"),
+            r => return Err(anyhow!("Invalid rule in flexible events: {:?}", r)),
         }
     }
     Ok(FlexEvents {
         occasion: condition,
         events,
+        span,
     })
 }
 
 fn parse_command(pair: Pair<Rule>) -> Result<CommandCall> {
+    let span = Span::from_pest(pair.as_span());
     let mut pairs = pair.into_inner();
     let command = get_next!(pairs);
     let mut argpairs = get_next!(pairs).into_inner();
@@ -119,17 +224,20 @@ fn parse_command(pair: Pair<Rule>) -> Result<CommandCall> {
         command: command.as_str().to_string(),
         args,
         plain: s.to_string(),
+        span,
     })
 }
 
 fn parse_property(pair: Pair<Rule>) -> Result<Property> {
+    let span = Span::from_pest(pair.as_span());
     let mut pairs = pair.into_inner();
     let name = get_next!(pairs);
     let value = get_next!(pairs);
     let data = parse_value(value)?;
     Ok(Property {
         name: name.as_str().to_string(),
-        data
+        data,
+        span,
     })
 }
 
@@ -137,13 +245,11 @@ fn parse_value(pair: Pair<Rule>) -> Result<Value> {
     match pair.as_rule() {
         Rule::FILTER => Ok(Value::NumFilter(parse_num_filter(pair)?)),
         Rule::DATE_FILTER => Ok(Value::DateFilter(parse_date_filter(pair)?)),
+        Rule::SELECTION => Ok(Value::DateFilter(parse_selection(pair)?)),
         Rule::IDENT => Ok(Value::Ident(parse_ident(pair)?)),
         Rule::NUM_FIELD => Ok(Value::Num(parse_numval(pair)?)),
         Rule::CARG | Rule::STRING => Ok(Value::String(pair.as_str().to_string())),
-        r => {
-            eprintln!("unexpected rule: {:?}", r);
-            unreachable!()
-        },
+        r => Err(anyhow!("Unexpected value: {:?}", r)),
     }
 }
 
@@ -152,15 +258,16 @@ fn parse_occasion(pair: Pair<Rule>) -> Result<DateTime> {
     let pair = get_next!(pairs);
     match pair.as_rule() {
         Rule::DATETIME => {
-            let date: Date = parse_date(pair)?;
+            let mut pairs = pair.into_inner();
+            let date: Date = get_match!(parse_date, pairs)?;
             let time: Time = get_match!(parse_time, pairs)?;
             Ok(DateTime {
                 date: Some(date),
                 time: Some(time),
-                ..Default::default()
+                tz: parse_time_zone(pairs.next())?,
             })
         }
-        Rule::DATE => {
+        Rule::DATE | Rule::RELATIVE_DATE => {
             // let mut pairs = pair.into_inner();
             let date: Date = parse_date(pair)?;
             Ok(DateTime {
@@ -172,6 +279,7 @@ fn parse_occasion(pair: Pair<Rule>) -> Result<DateTime> {
             let time: Time = parse_time(pair)?;
             Ok(DateTime {
                 time: Some(time),
+                tz: parse_time_zone(pairs.next())?,
                 ..Default::default()
             })
         }
@@ -179,12 +287,90 @@ fn parse_occasion(pair: Pair<Rule>) -> Result<DateTime> {
     }
 }
 
+fn parse_time_zone(pair: Option<Pair<Rule>>) -> Result<Option<TimeZoneChoice>> {
+    match pair {
+        Some(pair) if pair.as_rule() == Rule::TIME_ZONE => Ok(Some(parse_timezone(pair.as_str())?)),
+        _ => Ok(None),
+    }
+}
+
 fn parse_date(pair: Pair<Rule>) -> Result<Date> {
+    if pair.as_rule() == Rule::RELATIVE_DATE {
+        return Ok(Date {
+            relative: Some(parse_relative_date(pair)?),
+            ..Date::new()
+        });
+    }
+    let mut pairs = pair.into_inner();
+    let fst = get_next!(pairs);
+    let (year, year_date) = match fst.as_rule() {
+        Rule::WEEK_DATE => parse_week_date(fst)?,
+        Rule::ORDINAL_DATE => {
+            let mut pairs = fst.into_inner();
+            let year = get_match!(parse_numval, pairs)?;
+            let day = get_next!(pairs).as_str().parse::<u32>()?;
+            (year, YearDate::Ordinal(day))
+        }
+        _ => {
+            let year = parse_numval(fst)?;
+            let month = get_match!(parse_numval, pairs)?;
+            let day = get_match!(parse_numval, pairs)?;
+            return Ok(Date { year, month, day, relative: None, year_date: None });
+        }
+    };
+    Ok(Date {
+        year,
+        year_date: Some(year_date),
+        ..Date::new()
+    })
+}
+
+fn parse_week_date(pair: Pair<Rule>) -> Result<(NumVal, YearDate)> {
     let mut pairs = pair.into_inner();
     let year = get_match!(parse_numval, pairs)?;
-    let month = get_match!(parse_numval, pairs)?;
-    let day = get_match!(parse_numval, pairs)?;
-    Ok(Date { year, month, day })
+    let week = get_next!(pairs).as_str().parse::<u32>()?;
+    let weekday = match pairs.next() {
+        Some(weekday) => match weekday.as_str().parse::<u32>()? {
+            d @ 1..=7 => Some(d),
+            d => return Err(anyhow!("Days of the week go from 1 to 7, got {}", d)),
+        },
+        None => None,
+    };
+    Ok((year, YearDate::Week(week, weekday)))
+}
+
+fn parse_relative_date(pair: Pair<Rule>) -> Result<RelativeDate> {
+    let mut pairs = pair.into_inner();
+    let pair = get_next!(pairs);
+    match pair.as_rule() {
+        Rule::RELATIVE_DAY => Ok(RelativeDate::Days(match pair.as_str() {
+            "yesterday" => -1,
+            "tomorrow" => 1,
+            _ => 0,
+        })),
+        Rule::RELATIVE_SHIFT => {
+            let sign = if pair.as_str().starts_with('-') { -1 } else { 1 };
+            let mut pairs = pair.into_inner();
+            let n = get_next!(pairs).as_str().parse::<i64>()?;
+            let unit = match get_next!(pairs).as_str() {
+                "w" => 7,
+                _ => 1,
+            };
+            match n.checked_mul(unit * sign) {
+                Some(days) => Ok(RelativeDate::Days(days)),
+                None => Err(anyhow!("Date shift is too large")),
+            }
+        }
+        Rule::NEXT_WEEKDAY => {
+            let mut pairs = pair.into_inner();
+            let weekday = get_next!(pairs).as_str();
+            match weekday.parse::<chrono::Weekday>() {
+                Ok(weekday) => Ok(RelativeDate::Next(weekday)),
+                Err(_) => Err(anyhow!("Invalid weekday: {}", weekday)),
+            }
+        }
+        r => Err(anyhow!("Invalid relative date: {:?}", r)),
+    }
 }
 
 fn parse_time(pair: Pair<Rule>) -> Result<Time> {
@@ -222,41 +408,43 @@ pub fn parse_tod(pair: Pair<Rule>) -> Result<Tod> {
     }
 }
 
-pub fn parse_event(pair: Pair<Rule>) -> Result<Event> {
+pub fn parse_event(pair: Pair<Rule>, diagnostics: &mut Vec<Diagnostic>) -> Result<Event> {
+    let span = Span::from_pest(pair.as_span());
     // not quite sure if turn into pairs before or after function execution
     let mut pairs = pair.into_inner();
     let mut event: Event = {
         let raw = get_next!(pairs);
-        parse_event_header(raw)?
+        parse_event_header(raw, span)?
     };
     if pairs.peek().is_some() {
         // Assuming that all stuff are notes for now...
-        let notes = parse_notes(&mut pairs)?;
+        let notes = parse_notes(&mut pairs, diagnostics)?;
         event.notes = Some(notes);
     }
     Ok(event)
 }
 
-fn parse_note(pair: Pair<Rule>) -> &str {
+fn parse_note(pair: Pair<'_, Rule>) -> &str {
     pair.as_str()
 }
 
-fn parse_notes(pairs: &mut Pairs<Rule>) -> Result<Notes> {
+fn parse_notes(pairs: &mut Pairs<'_, Rule>, diagnostics: &mut Vec<Diagnostic>) -> Result<Notes> {
     let mut description = String::new();
     let mut properties = vec![];
     for note in pairs {
         match note.as_rule() {
             Rule::PROPERTY => {
-                properties.push(parse_property(note).unwrap());
+                let span = Span::from_pest(note.as_span());
+                match parse_property(note) {
+                    Ok(property) => properties.push(property),
+                    Err(e) => diagnostics.push(Diagnostic::from_error("parse", &span.wrap(e))),
+                }
             }
             Rule::NOTE => {
                 description.push_str(parse_note(note));
                 description.push('\n');
             }
-            _ => {
-                eprintln!("unexpected rule: {:?}", note.as_rule());
-                unreachable!()
-            }
+            r => return Err(anyhow!("Unexpected rule in notes: {:?}", r)),
         }
     }
     Ok(Notes{
@@ -265,15 +453,23 @@ fn parse_notes(pairs: &mut Pairs<Rule>) -> Result<Notes> {
     })
 }
 
-fn parse_event_header(pair: Pair<Rule>) -> Result<Event> {
+fn parse_event_header(pair: Pair<Rule>, span: Span) -> Result<Event> {
     let mut pairs = pair.into_inner();
-    let timerange = get_match!(parse_timerange, pairs)?;
+    let mut timerange = get_match!(parse_timerange, pairs)?;
+    if pairs.peek().map(|p| p.as_rule()) == Some(Rule::DURATION) {
+        let minutes = get_match!(parse_duration, pairs)?;
+        match &mut timerange {
+            Range::Duration(duration) => duration.duration = NumVal::Number(minutes),
+            _ => return Err(anyhow!("A duration can only follow a start time")),
+        }
+    }
     let name = get_match!(parse_note, pairs).to_string();
 
     Ok(Event {
         range: timerange,
         name,
         notes: None,
+        span,
     })
 }
 
@@ -293,7 +489,7 @@ fn parse_timerange(pair: Pair<Rule>) -> Result<Range> {
                     duration: NumVal::Unsure,
                 })),
                 None => match occasion.date {
-                    None => return Err(anyhow!("Invalid occasion")),
+                    None => Err(anyhow!("Invalid occasion")),
                     Some(date) => Ok(AllDay(date)),
                 },
             }
@@ -302,6 +498,40 @@ fn parse_timerange(pair: Pair<Rule>) -> Result<Range> {
     }
 }
 
+/// Parses a duration such as `+1h30m` into minutes.
+fn parse_duration(pair: Pair<Rule>) -> Result<i64> {
+    let mut minutes: i64 = 0;
+    for part in pair.into_inner().filter(|p| p.as_rule() == Rule::DURATION_PART) {
+        let mut pairs = part.into_inner();
+        let n = get_next!(pairs).as_str().parse::<i64>()?;
+        let unit = match get_next!(pairs).as_str() {
+            "d" => 24 * 60,
+            "h" => 60,
+            _ => 1,
+        };
+        minutes = n
+            .checked_mul(unit)
+            .and_then(|n| minutes.checked_add(n))
+            .ok_or_else(|| anyhow!("Duration is too long"))?;
+    }
+    Ok(minutes)
+}
+
+/// Parses a bare duration such as `1h30m` into minutes.
+pub fn parse_duration_str(source: &str) -> Result<i64> {
+    let mut pairs = BlokParser::parse(Rule::DURATION_VALUE, source)
+        .map_err(|_| anyhow!("Invalid duration: {}", source))?;
+    parse_duration(get_next!(pairs))
+}
+
+/// Parses a bare date such as `2023-9-4` or `next mon`.
+pub fn parse_date_str(source: &str) -> Result<Date> {
+    let mut pairs = BlokParser::parse(Rule::DATE_VALUE, source)
+        .map_err(|_| anyhow!("Invalid date: {}", source))?;
+    let mut inner = get_next!(pairs).into_inner();
+    parse_date(get_next!(inner))
+}
+
 fn parse_numval(pair: Pair<Rule>) -> Result<NumVal> {
     Ok(match pair.as_str().parse::<i64>() {
         Ok(n) => NumVal::Number(n),
@@ -341,6 +571,7 @@ lazy_static! {
         PrattParser::new()
             .op(Op::infix(OR, Left) | Op::infix(AND, Left))
             .op(Op::prefix(NOT))
+            .op(Op::postfix(SHIFT))
     };
 }
 
@@ -351,7 +582,26 @@ pub fn parse_date_filter(pair: Pair<Rule>) -> Result<BDF<Date>> {
             Rule::FILTER => parse_date_filter(primary),
             Rule::UNIT_DATE_FILTER => parse_date_filter(primary),
             Rule::DATE_FILTER => parse_date_filter(primary),
+            Rule::SELECTION => parse_selection(primary),
             Rule::IDENT => parse_ident_date_filter(primary),
+            Rule::NTH_WEEKDAY => {
+                let nth = parse_nth_weekday(primary)?;
+                Ok(Box::new(nth) as BDF<Date>)
+            }
+            Rule::TERM_WEEK => parse_term_week(primary),
+            Rule::ISO_WEEK => {
+                let mut pairs = primary.into_inner();
+                let start = get_match!(parse_numval, pairs)?;
+                let end = match pairs.next() {
+                    Some(end) => parse_numval(end)?,
+                    None => start,
+                };
+                Ok(Box::new(IsoWeek { weeks: NumRange { start, end } }) as BDF<Date>)
+            }
+            Rule::INTERVAL => {
+                let interval = parse_interval(primary)?;
+                Ok(Box::new(interval) as BDF<Date>)
+            }
             Rule::RANGE => {
                 let trange = parse_timerange(primary)?;
                 Ok(Box::new(trange) as BDF<Date>)
@@ -360,10 +610,7 @@ pub fn parse_date_filter(pair: Pair<Rule>) -> Result<BDF<Date>> {
                 let date = parse_flex_date(primary)?;
                 Ok(Box::new(date) as BDF<Date>)
             }
-            _ => {
-                eprintln!("Invalid date filter: {:?}", primary);
-                todo!()
-            }
+            r => Err(anyhow!("Invalid date filter: {:?}", r)),
         })
         .map_infix(|lhs, op, rhs| {
             let lhs = lhs?;
@@ -379,7 +626,7 @@ pub fn parse_date_filter(pair: Pair<Rule>) -> Result<BDF<Date>> {
                     rhs,
                     op: filter::Op::And,
                 })),
-                _ => unreachable!("Invalid infix rule"),
+                r => Err(anyhow!("Invalid infix rule: {:?}", r)),
             }
         })
         .map_prefix(|op, rhs| match op.as_rule() {
@@ -387,11 +634,112 @@ pub fn parse_date_filter(pair: Pair<Rule>) -> Result<BDF<Date>> {
                 let target = rhs?;
                 Ok(Box::new(ExcludeFilt { target }))
             }
-            _ => unreachable!(),
+            r => Err(anyhow!("Invalid prefix rule: {:?}", r)),
+        })
+        .map_postfix(|lhs, op| match op.as_rule() {
+            Rule::SHIFT => parse_shift(lhs?, op),
+            r => Err(anyhow!("Invalid postfix rule: {:?}", r)),
         })
         .parse(pairs)
 }
 
+fn parse_shift(target: BDF<Date>, pair: Pair<Rule>) -> Result<BDF<Date>> {
+    let sign = if pair.as_str().starts_with('-') { -1 } else { 1 };
+    let mut pairs = pair.into_inner();
+    let count = get_next!(pairs).as_str().parse::<i64>()?;
+    let unit = get_next!(pairs);
+    let (days, unit) = match unit.as_rule() {
+        Rule::INTERVAL_UNIT if unit.as_str().starts_with('w') => (count.checked_mul(7), None),
+        Rule::INTERVAL_UNIT => (Some(count), None),
+        _ => (Some(count), Some(parse_ident_date_filter(unit)?)),
+    };
+    let n = days.ok_or_else(|| anyhow!("Shift is too long"))? * sign;
+    if n == 0 {
+        return Err(anyhow!("A shift must be at least a day"));
+    }
+    Ok(Box::new(Shift { target, n, unit }))
+}
+
+fn parse_nth_weekday(pair: Pair<Rule>) -> Result<NthWeekday> {
+    let mut pairs = pair.into_inner();
+    let ordinal = get_next!(pairs).as_str();
+    let n = match ordinal {
+        "last" => -1,
+        _ => ordinal.trim_end_matches(char::is_alphabetic).parse::<i64>()?,
+    };
+    if n == 0 {
+        return Err(anyhow!("Weekdays are counted from 1, or from -1 for the last one"));
+    }
+    let weekday = get_next!(pairs).as_str();
+    match weekday.parse::<chrono::Weekday>() {
+        Ok(weekday) => Ok(NthWeekday { weekday, n }),
+        Err(_) => Err(anyhow!("Invalid weekday: {}", weekday)),
+    }
+}
+
+fn parse_selection(pair: Pair<Rule>) -> Result<BDF<Date>> {
+    let mut pairs = pair.into_inner();
+    let filter = get_match!(parse_date_filter, pairs)?;
+    let mut n = 0;
+    let mut period = None;
+    for pair in get_next!(pairs).into_inner() {
+        match pair.as_rule() {
+            Rule::SELECT_INDEX => n = pair.as_str().parse::<i64>()?,
+            Rule::PERIOD => {
+                period = Some(match pair.as_str() {
+                    "week" => Period::Week,
+                    "month" => Period::Month,
+                    _ => Period::Year,
+                })
+            }
+            r => return Err(anyhow!("Invalid selection: {:?}", r)),
+        }
+    }
+    if n == 0 {
+        return Err(anyhow!("Matches are counted from 1, or from -1 for the last one"));
+    }
+    Ok(Box::new(Select { filter, n, period }))
+}
+
+fn parse_term_week(pair: Pair<Rule>) -> Result<BDF<Date>> {
+    let mut pairs = pair.into_inner();
+    let term = parse_ident(get_next!(pairs))?;
+    let weeks = get_next!(pairs);
+    let weeks: BDF<NumVal> = match weeks.as_rule() {
+        Rule::WEEK_PARITY => {
+            let parity = if weeks.as_str() == "odd" { 1 } else { 0 };
+            Box::new(DynFilter {
+                filter: Rc::new(move |n: &NumVal, _| matches!(n, NumVal::Number(n) if *n > 0 && n % 2 == parity)),
+                name: weeks.as_str().to_string(),
+            })
+        }
+        Rule::NUM_RANGE => Box::new(parse_numrange(weeks)?),
+        _ => Box::new(parse_numval(weeks)?),
+    };
+    Ok(Box::new(TermWeek { term, weeks }))
+}
+
+fn parse_interval(pair: Pair<Rule>) -> Result<Interval> {
+    let mut count = 1;
+    let mut unit = 1;
+    let mut anchor = None;
+    for pair in pair.into_inner() {
+        match pair.as_rule() {
+            Rule::NUM_FIELD => count = pair.as_str().parse::<i64>()?,
+            Rule::INTERVAL_UNIT => unit = if pair.as_str().starts_with('w') { 7 } else { 1 },
+            _ => anchor = Some(parse_date(pair)?),
+        }
+    }
+    let days = count.checked_mul(unit).ok_or_else(|| anyhow!("Interval is too long"))?;
+    if days == 0 {
+        return Err(anyhow!("An interval must be at least a day"));
+    }
+    Ok(Interval {
+        anchor: anchor.ok_or_else(|| anyhow!("An interval needs a date to start from"))?,
+        days,
+    })
+}
+
 pub fn parse_num_filter(pair: Pair<Rule>) -> Result<BDF<NumVal>> {
     let pairs = pair.into_inner();
     PRATT_PARSER
@@ -405,10 +753,7 @@ pub fn parse_num_filter(pair: Pair<Rule>) -> Result<BDF<NumVal>> {
                 Ok(Box::new(num) as BDF<NumVal>)
             }
             Rule::UNSURE => Ok(Box::new(NumVal::Unsure) as BDF<NumVal>),
-            _ => {
-                eprintln!("Invalid number filter: {:?}", primary);
-                todo!()
-            }
+            r => Err(anyhow!("Invalid number filter: {:?}", r)),
         })
         .map_infix(|lhs, op, rhs| {
             let lhs = lhs?;
@@ -424,7 +769,7 @@ pub fn parse_num_filter(pair: Pair<Rule>) -> Result<BDF<NumVal>> {
                     rhs,
                     op: filter::Op::And,
                 })),
-                _ => unreachable!("Invalid infix rule"),
+                r => Err(anyhow!("Invalid infix rule: {:?}", r)),
             }
         })
         .map_prefix(|op, rhs| match op.as_rule() {
@@ -432,20 +777,257 @@ pub fn parse_num_filter(pair: Pair<Rule>) -> Result<BDF<NumVal>> {
                 let target = rhs?;
                 Ok(Box::new(ExcludeFilt { target }))
             }
-            _ => unreachable!(),
+            r => Err(anyhow!("Invalid prefix rule: {:?}", r)),
         })
         .parse(pairs)
 }
 
+fn parse_flex_date_filter(pair: Pair<Rule>) -> Result<BDF<Date>> {
+    match pair.as_rule() {
+        Rule::FLEX_DATE => Ok(Box::new(parse_flex_date(pair)?)),
+        Rule::DATE_FILTER => parse_date_filter(pair),
+        Rule::SELECTION => parse_selection(pair),
+        r => Err(anyhow!("Invalid flexible date: {:?}", r)),
+    }
+}
+
 pub fn parse_flex_occasion(pair: Pair<Rule>) -> Result<FlexOccasion> {
     let mut pairs = pair.into_inner();
     let fst = get_next!(pairs);
+    let window = match pairs.next() {
+        Some(window) => Some(parse_window(window)?),
+        None => None,
+    };
     match fst.as_rule() {
-        Rule::FLEX_DATETIME => todo!(),
-        Rule::DATE_FILTER => {
-            let filter = parse_date_filter(fst)?;
-            Ok(FlexOccasion::Filter(filter))
+        Rule::FLEX_DATETIME => {
+            let mut inner = fst.into_inner();
+            let filter = get_match!(parse_flex_date_filter, inner)?;
+            let time = get_match!(parse_time, inner)?;
+            Ok(FlexOccasion {
+                filter,
+                time: Some(time),
+                window,
+            })
+        }
+        Rule::FLEX_DATE | Rule::DATE_FILTER | Rule::SELECTION => Ok(FlexOccasion {
+            filter: parse_flex_date_filter(fst)?,
+            time: None,
+            window,
+        }),
+        Rule::TIME => Err(anyhow!("A time on its own is not a flexible occasion")),
+        r => Err(anyhow!("Invalid flexible occasion: {:?}", r)),
+    }
+}
+
+fn parse_window(pair: Pair<Rule>) -> Result<Window> {
+    let mut pairs = pair.into_inner();
+    let fst = get_next!(pairs);
+    match fst.as_rule() {
+        Rule::IDENT => Ok(Window::Named(parse_ident(fst)?)),
+        _ => {
+            let start = parse_date(fst)?;
+            let end = get_match!(parse_date, pairs)?;
+            Ok(Window::Between(start, end))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::Environment;
+    use crate::resolver::resolve_event;
+
+    fn parse(source: &str) -> Vec<Record> {
+        let (records, diagnostics) = parse_source(source);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        records
+    }
+
+    #[test]
+    fn test_spans() {
+        let records = parse("2023-4-4\n10am wake up\n- a note\n@url \"x\"\n/t todo\n");
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].span().line, 1);
+        match &records[1] {
+            Record::Event(event) => {
+                assert_eq!((event.span.line, event.span.col), (2, 1));
+                let prop = &event.notes.as_ref().unwrap().properties[0];
+                assert_eq!((prop.span.line, prop.span.col), (4, 1));
+            }
+            r => panic!("expected event, got {:?}", r),
+        }
+        assert_eq!(records[2].span().line, 5);
+    }
+
+    #[test]
+    fn test_recovery() {
+        let (records, diagnostics) = parse_source("2023-4-4\n{mon or}\n9am x\n--5\n/set \n10am y\n{tue}\n11am z\n");
+        let lines: Vec<usize> = diagnostics.iter().map(|d| d.span.unwrap().line).collect();
+        assert_eq!(lines, vec![2, 5]);
+        assert_eq!(diagnostics[0].span.unwrap().col, 8);
+        let events = records
+            .iter()
+            .filter(|r| matches!(r, Record::Event(_) | Record::FlexEvents(_)))
+            .count();
+        assert_eq!(events, 3);
+    }
+
+    #[test]
+    fn test_duration() {
+        let records = parse("10am +1h30m Deep work\n10am (45m) Standup\n10am +1hour x\n9am (2d) Trip\n");
+        let durations: Vec<(NumVal, &str)> = records
+            .iter()
+            .map(|r| match r {
+                Record::Event(Event { range: Range::Duration(d), name, .. }) => (d.duration, name.as_str()),
+                r => panic!("expected event, got {:?}", r),
+            })
+            .collect();
+        assert_eq!(
+            durations,
+            vec![
+                (NumVal::Number(90), "Deep work"),
+                (NumVal::Number(45), "Standup"),
+                (NumVal::Unsure, "+1hour x"),
+                (NumVal::Number(2880), "Trip"),
+            ]
+        );
+        let (_, diagnostics) = parse_source("2023-4-5 +2d trip\n");
+        assert_eq!(diagnostics.len(), 1);
+    }
+
+    #[test]
+    fn test_time_zone() {
+        let records = parse("9am America/Los_Angeles Flight\n9am CEO sync\n2023-4-4 10am Europe/Paris~11am +05:30 x\n10am y\n@tz America/New_York\n");
+        let zones: Vec<(Option<TimeZoneChoice>, Option<TimeZoneChoice>)> = records
+            .iter()
+            .map(|r| match r {
+                Record::Event(Event { range: Range::Duration(d), .. }) => (d.start.tz, None),
+                Record::Event(Event { range: Range::Time(tr), .. }) => (tr.start.tz, tr.end.tz),
+                r => panic!("expected event, got {:?}", r),
+            })
+            .collect();
+        let offset = chrono::FixedOffset::east_opt(5 * 3600 + 30 * 60).unwrap();
+        assert_eq!(
+            zones,
+            vec![
+                (Some(TimeZoneChoice::Named(chrono_tz::America::Los_Angeles)), None),
+                (None, None),
+                (Some(TimeZoneChoice::Named(chrono_tz::Europe::Paris)), Some(TimeZoneChoice::Offset(offset))),
+                (None, None),
+            ]
+        );
+        match &records[3] {
+            Record::Event(event) => assert_eq!(event.notes.as_ref().unwrap().properties[0].name, "tz"),
+            r => panic!("expected event, got {:?}", r),
+        }
+        // Abbreviations after a time are part of the name
+        for (abbreviation, _) in crate::preset::TIMEZONE_ABBREVIATIONS {
+            match &parse(&format!("9am {} x\n", abbreviation))[0] {
+                Record::Event(Event { range: Range::Duration(d), name, .. }) => {
+                    assert!(d.start.tz.is_none() && *name == format!("{} x", abbreviation), "{}", abbreviation)
+                }
+                r => panic!("expected event, got {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn test_time_zone_words() {
+        let names: Vec<String> = parse("3pm PT appointment\n9am CT scan\n")
+            .into_iter()
+            .map(|r| match r {
+                Record::Event(Event { range: Range::Duration(d), name, .. }) if d.start.tz.is_none() => name,
+                r => panic!("expected event without a zone, got {:?}", r),
+            })
+            .collect();
+        assert_eq!(names, ["PT appointment", "CT scan"]);
+    }
+
+    #[test]
+    fn test_relative_dates() {
+        let (records, diagnostics) = parse_source("todays note\ntomorrow\nnext friday 9am x\n-2w 9am y\n");
+        assert!(diagnostics.is_empty());
+        let relative: Vec<Option<RelativeDate>> = records
+            .iter()
+            .map(|r| match r {
+                Record::Occasion(dt, _) => dt.date.and_then(|d| d.relative),
+                Record::Event(Event { range: Range::Duration(d), .. }) => d.start.date.and_then(|d| d.relative),
+                Record::Event(Event { range: Range::AllDay(d), .. }) => d.relative,
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            relative,
+            vec![
+                None,
+                Some(RelativeDate::Days(1)),
+                Some(RelativeDate::Next(chrono::Weekday::Fri)),
+                Some(RelativeDate::Days(-14)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_relative_date_notes() {
+        let (records, diagnostics) = parse_source("today we discussed the roadmap\n- tomorrow call dad\ntomorrow\n9am x\ntoday it rained\n");
+        assert!(diagnostics.is_empty());
+        assert_eq!(records.len(), 4);
+        assert!(matches!(&records[0], Record::Note(n, _) if n == "today we discussed the roadmap"), "{:?}", records[0]);
+        assert!(matches!(&records[2], Record::Occasion(..)), "{:?}", records[2]);
+        match &records[3] {
+            Record::Event(event) => assert_eq!(event.notes.as_ref().map(|n| n.description.as_str()), Some("today it rained\n")),
+            r => panic!("expected event, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_filter_argument() {
+        let records = parse("/set x {mon or fri}\n");
+        match &records[0] {
+            Record::Command(cmd) => {
+                assert_eq!(cmd.args.len(), 2);
+                assert!(matches!(cmd.args[1], Value::DateFilter(_)), "{:?}", cmd.args[1]);
+            }
+            r => panic!("expected command, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_year_dates() {
+        let records = parse("2023-W15\n-W2-7 9am x\n2023-100 y\n2023-10-1\n");
+        let year_dates: Vec<Option<YearDate>> = records
+            .iter()
+            .map(|r| match r {
+                Record::Occasion(dt, _) => dt.date.and_then(|d| d.year_date),
+                Record::Event(Event { range: Range::Duration(d), .. }) => d.start.date.and_then(|d| d.year_date),
+                Record::Event(Event { range: Range::AllDay(d), .. }) => d.year_date,
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            year_dates,
+            vec![
+                Some(YearDate::Week(15, None)),
+                Some(YearDate::Week(2, Some(7))),
+                Some(YearDate::Ordinal(100)),
+                None,
+            ]
+        );
+        let (_, diagnostics) = parse_source("2023-W15-8 x\n");
+        assert_eq!(diagnostics.len(), 1);
+    }
+
+    #[test]
+    fn test_resolve_error_span() {
+        let records = parse("2023-4-4\n\n25:00 too late\n");
+        let env = Environment::from_exact(ExactDateTime::from_ymd_hms(2023, 1, 1, 0, 0, 0));
+        if let Record::Event(event) = &records[1] {
+            let err = resolve_event(event, &env).unwrap_err();
+            let spanned = err.downcast_ref::<SpannedError>().unwrap();
+            assert_eq!(spanned.span.line, 3);
+        } else {
+            panic!("expected event");
         }
-        sth => unreachable!("Invalid flex occasion rule: {:?}", sth),
     }
 }
@@ -5,15 +5,18 @@ mod workalendar;
 
 use std::rc::Rc;
 
+use crate::diagnostic::Diagnostic;
 use crate::environment::Environment;
 use crate::importer::{ics_to_records, import_ics, SetFilter};
-use crate::ir::command::{Command, CommandRes, CommandCall, CmdFn};
-use crate::ir::filter::ExcludeFilt;
-use crate::ir::ident::{DynFilter, IdentData};
-use crate::ir::{Date, ExactDate, Value};
-use crate::resolver::{resolve_date, ResolverAction};
+use crate::ir::command::{Command, CommandCall, CmdFn};
+use crate::ir::filter::{ExcludeFilt, Weekdays};
+use crate::ir::ident::IdentData;
+use crate::ir::{Date, TimeZoneChoice, Value};
+use crate::parser::{parse_date_str, parse_duration_str};
+use crate::resolver::{resolve_date, ResolverAction, DEFAULT_DURATION};
 use anyhow::{anyhow, Result};
-use chrono::{Datelike, Weekday};
+use chrono::Weekday;
+use chrono_tz::Tz;
 
 
 
@@ -21,12 +24,6 @@ use chrono::{Datelike, Weekday};
 #[cfg(not(target_family = "wasm"))]
 use self::workalendar::{get_holiday, get_workdays};
 
-impl ExactDate {
-    fn weekday(&self) -> Result<Weekday> {
-        Ok(self.to_chrono()?.weekday())
-    }
-}
-
 fn insert_command(env: &Environment, name:&str, arity: usize, func: CmdFn) -> Result<()> {
     env.set(
         name,
@@ -38,19 +35,109 @@ fn insert_command(env: &Environment, name:&str, arity: usize, func: CmdFn) -> Re
     )
 }
 
+// Common abbreviations, mapped to a zone that observes them so daylight saving is still followed:
+// a standard-time name like `EST`, `CET` or `WET` means the same zone as its summer name.
+// GMT is the one exception, as it is also used for UTC, and stays at +00:00 all year round.
+pub(crate) const TIMEZONE_ABBREVIATIONS: &[(&str, Tz)] = &[
+    ("UTC", Tz::UTC),
+    ("GMT", Tz::Etc__GMT),
+    ("WET", Tz::Europe__Lisbon),
+    ("BST", Tz::Europe__London),
+    ("WEST", Tz::Europe__Lisbon),
+    ("CET", Tz::Europe__Paris),
+    ("CEST", Tz::Europe__Paris),
+    ("EET", Tz::Europe__Athens),
+    ("EEST", Tz::Europe__Athens),
+    ("SGT", Tz::Asia__Singapore),
+    ("HKT", Tz::Asia__Hong_Kong),
+    ("JST", Tz::Asia__Tokyo),
+    ("KST", Tz::Asia__Seoul),
+    ("AEST", Tz::Australia__Sydney),
+    ("AEDT", Tz::Australia__Sydney),
+    ("NZST", Tz::Pacific__Auckland),
+    ("NZDT", Tz::Pacific__Auckland),
+    ("HST", Tz::Pacific__Honolulu),
+    ("AKST", Tz::America__Anchorage),
+    ("AKDT", Tz::America__Anchorage),
+    ("PST", Tz::America__Los_Angeles),
+    ("PDT", Tz::America__Los_Angeles),
+    ("MST", Tz::America__Denver),
+    ("MDT", Tz::America__Denver),
+    ("EST", Tz::America__New_York),
+    ("EDT", Tz::America__New_York),
+    ("ET", Tz::America__New_York),
+    ("CDT", Tz::America__Chicago),
+    ("CT", Tz::America__Chicago),
+    ("MT", Tz::America__Denver),
+    ("PT", Tz::America__Los_Angeles),
+    ("AKT", Tz::America__Anchorage),
+];
+
+// Abbreviations used by several zones, along with the zones they can mean
+const AMBIGUOUS_ABBREVIATIONS: &[(&str, &str)] = &[
+    ("IST", "Asia/Kolkata, Europe/Dublin or Asia/Jerusalem"),
+    ("CST", "America/Chicago, Asia/Shanghai or America/Havana"),
+];
+
+/// Parses an IANA name such as `America/New_York`, an abbreviation such as `PDT`, or an offset such as `+08:00`.
+pub fn parse_timezone(name: &str) -> Result<TimeZoneChoice> {
+    let name = name.trim();
+    if let Some((abbr, zones)) = AMBIGUOUS_ABBREVIATIONS.iter().find(|(abbr, _)| abbr.eq_ignore_ascii_case(name)) {
+        return Err(anyhow!("{} is used by several timezones, use one of {} instead", abbr, zones));
+    }
+    // Abbreviations come first, as some of them are also zones without daylight saving, eg. `EST`
+    let abbreviation = TIMEZONE_ABBREVIATIONS
+        .iter()
+        .find(|(abbr, _)| abbr.eq_ignore_ascii_case(name));
+    if let Some((_, tz)) = abbreviation {
+        return Ok(TimeZoneChoice::Named(*tz));
+    }
+    if let Ok(tz) = name.parse::<Tz>() {
+        return Ok(TimeZoneChoice::Named(tz));
+    }
+    // Unknown names would be read as UTC, so only offsets are left to dateparser
+    match dateparser::timezone::parse(name) {
+        Ok(offset) if name.starts_with(['+', '-']) => Ok(TimeZoneChoice::Offset(offset)),
+        _ => Err(anyhow!("Unrecognized timezone: {}", name)),
+    }
+}
+
 fn insert_timezone(env: &mut Environment) -> Result<()> {
     let fnc = Rc::new(|_env: &Environment, x: &CommandCall| {
-        if let Ok(tz) = dateparser::timezone::parse(x.plain.as_str()){
-            Ok(Some(vec![ResolverAction::SetTimeZone(
-                crate::ir::TimeZoneChoice::Offset(tz))
-            ]))
-        }else{Ok(None)}
+        Ok(Some(vec![ResolverAction::SetTimeZone(parse_timezone(&x.plain)?)]))
     });
     insert_command(env, "timezone", 0, fnc.clone())?;
     insert_command(env, "tz", 0, fnc)?;
     Ok(())
 }
 
+fn insert_timeblock(env: &mut Environment) -> Result<()> {
+    insert_command(env, "timeblock", 0, Rc::new(|_env: &Environment, x: &CommandCall| {
+        let cap = match x.plain.trim() {
+            "" | "on" => Some(DEFAULT_DURATION),
+            "off" => None,
+            cap => Some(parse_duration_str(cap)? as u64),
+        };
+        Ok(Some(vec![ResolverAction::SetTimeBlocking(cap)]))
+    }))
+}
+
+fn insert_term(env: &mut Environment) -> Result<()> {
+    insert_command(env, "term", 0, Rc::new(|env: &Environment, x: &CommandCall| {
+        let (name, start) = x
+            .plain
+            .trim()
+            .split_once(' ')
+            .ok_or_else(|| anyhow!("Usage: /term [name] [start date]"))?;
+        if !name.starts_with(|c: char| c.is_ascii_alphabetic()) || !name.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(anyhow!("Invalid term name: {}", name));
+        }
+        let start = resolve_date(&parse_date_str(start.trim())?, env)?;
+        env.set(name, IdentData::Value(Value::Date(Date::from_ymd(start.year, start.month, start.day))))?;
+        Ok(None)
+    }))
+}
+
 #[cfg(not(target_family = "wasm"))]
 fn insert_region(env: &mut Environment) -> Result<()> {
     env.set(
@@ -94,8 +181,10 @@ fn insert_region(env: &mut Environment) -> Result<()> {
                             filt,
                         ))))),
                     )?;
+                    Ok(None)
+                } else {
+                    Err(anyhow!("The argument must be an identity."))
                 }
-                Ok(None)
             }),
         }),
     )?;
@@ -115,7 +204,7 @@ fn insert_commands(env: &mut Environment) -> Result<()> {
             func: Rc::new(|env: &Environment, x: &CommandCall| {
                 if let Value::Ident(ident) = &x.args[0] {
                     if let Some(dat) = env.get(&ident.name) {
-                        println!("{} : {:?}", &ident.name, dat);
+                        env.report(Diagnostic::info("print", format!("{} : {:?}", &ident.name, dat)).with_span(x.span));
                         Ok(None)
                     } else {
                         Err(anyhow!(format!("Identity {} not found", &ident.name)))
@@ -195,7 +284,7 @@ fn insert_commands(env: &mut Environment) -> Result<()> {
                                 Err(e) => Err(anyhow!(e)),
                             }
                         }
-                        Ok(None)
+                        Err(anyhow!("unsupported file format"))
                     }else{Err(anyhow!("The two arguments must be `ident`s"))}
                 }
                 n => Err(anyhow!("Unexpected argument length: {}", n))
@@ -205,7 +294,11 @@ fn insert_commands(env: &mut Environment) -> Result<()> {
 
     // todo function
     insert_command(env, "t", 0, Rc::new(|_env: &Environment, x: &CommandCall|{
-        Ok(Some(vec![ResolverAction::InsertTodo(Todo::from_string(x.plain.clone())?)]))
+        let todo = Todo {
+            span: Some(x.span),
+            ..Todo::from_string(x.plain.clone())?
+        };
+        Ok(Some(vec![ResolverAction::InsertTodo(todo)]))
     }))?;
 
     Ok(())
@@ -231,30 +324,18 @@ fn insert_weekdays(env: &mut Environment) -> Result<()> {
         "sun",
     ];
     for w in weekdays {
-        let wkday = w.parse::<Weekday>().unwrap();
-        let filt = DynFilter {
-            filter: Rc::new(move |d: &Date, env: Option<&Environment>| {
-                resolve_date(d, env.unwrap()).unwrap().weekday().unwrap() == wkday
-            }),
-            name: w.to_string(),
+        let filt = Weekdays {
+            days: vec![w.parse::<Weekday>().unwrap()],
         };
         env.set(w, IdentData::Value(DateFilter(Box::new(filt))))?;
     }
     // Insert workday and weekend
-    let workday = DynFilter {
-        filter: Rc::new(move |d: &Date, env: Option<&Environment>| {
-            let wkday = resolve_date(d, env.unwrap()).unwrap().weekday().unwrap();
-            wkday != Weekday::Sat && wkday != Weekday::Sun
-        }),
-        name: "workday".to_string(),
+    let workday = Weekdays {
+        days: vec![Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri],
     };
     env.set("workday", IdentData::Value(DateFilter(Box::new(workday))))?;
-    let weekend = DynFilter {
-        filter: Rc::new(move |d: &Date, env: Option<&Environment>| {
-            let wkday = resolve_date(d, env.unwrap()).unwrap().weekday().unwrap();
-            wkday == Weekday::Sat || wkday == Weekday::Sun
-        }),
-        name: "weekend".to_string(),
+    let weekend = Weekdays {
+        days: vec![Weekday::Sat, Weekday::Sun],
     };
     env.set("weekend", IdentData::Value(DateFilter(Box::new(weekend))))?;
     Ok(())
@@ -267,6 +348,8 @@ pub fn insert_preset(env: &mut Environment) -> Result<()> {
     insert_commands(env)?;
     insert_region(env)?;
     insert_timezone(env)?;
+    insert_timeblock(env)?;
+    insert_term(env)?;
     Ok(())
 }
 
@@ -277,5 +360,54 @@ pub fn insert_preset(env: &mut Environment) -> Result<()> {
     insert_weekdays(env)?;
     insert_commands(env)?;
     insert_timezone(env)?;
+    insert_timeblock(env)?;
+    insert_term(env)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_timezone() {
+        let named = |tz| Some(TimeZoneChoice::Named(tz));
+        assert_eq!(parse_timezone("America/New_York").ok(), named(Tz::America__New_York));
+        assert_eq!(parse_timezone(" pdt ").ok(), named(Tz::America__Los_Angeles));
+        assert_eq!(parse_timezone("EST").ok(), named(Tz::America__New_York));
+        assert_eq!(parse_timezone("utc").ok(), named(Tz::UTC));
+        assert!(matches!(parse_timezone("+0800"), Ok(TimeZoneChoice::Offset(_))));
+        assert!(parse_timezone("Mars/Olympus_Mons").is_err());
+        assert!(parse_timezone("IST").unwrap_err().to_string().contains("Asia/Kolkata"));
+    }
+
+    #[test]
+    fn test_gmt_in_summer() -> Result<()> {
+        let (records, _) = crate::tb_to_records("2023-7-1\n/tz GMT\n9am x\n")?;
+        let (resolved, _) = crate::records_to_resolved(records, crate::ir::ExactDateTime::from_ymd_hms(2023, 1, 1, 0, 0, 0))?;
+        let (json, _) = crate::resolved_to_json(resolved)?;
+        assert!(json.contains("\"start\": \"2023-07-01T09:00:00+00:00\""), "{}", json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wet_in_summer() -> Result<()> {
+        let (records, _) = crate::tb_to_records("2023-7-1\n/tz WET\n9am x\n")?;
+        let (resolved, _) = crate::records_to_resolved(records, crate::ir::ExactDateTime::from_ymd_hms(2023, 1, 1, 0, 0, 0))?;
+        let (json, _) = crate::resolved_to_json(resolved)?;
+        assert!(json.contains("\"start\": \"2023-07-01T09:00:00+01:00\""), "{}", json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_print() -> Result<()> {
+        let (records, _) = crate::tb_to_records("/set x 3\n/print x\n")?;
+        let (_, diagnostics) = crate::records_to_resolved(records, crate::ir::ExactDateTime::from_ymd_hms(2023, 1, 1, 0, 0, 0))?;
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, crate::diagnostic::Severity::Info);
+        assert_eq!(diagnostics[0].code, "print");
+        assert!(diagnostics[0].message.starts_with("x : "));
+        assert_eq!(diagnostics[0].span.map(|s| s.line), Some(2));
+        Ok(())
+    }
+}
@@ -1,26 +1,45 @@
+use crate::diagnostic::Diagnostic;
 use crate::environment::Environment;
 use crate::ir::NumVal::{Number, Unsure};
 use crate::ir::*;
-use crate::preset::insert_preset;
+use crate::preset::{insert_preset, parse_timezone};
 use anyhow::{anyhow, Result};
+use chrono::{Datelike, NaiveDate};
 
 use std::rc::Rc;
 
 use crate::ir::ident::{Ident, IdentData};
 use crate::ir::Todo;
+use crate::ir::recurrence::{Recurrence, RecurrenceRule};
 
 // TODO: Change all resolve to Result<> based
 // TODO: Use a struct to represent the resolver state.
 
+/// Length of an event that only has a start time, in minutes
+pub const DEFAULT_DURATION: u64 = 30;
+
+/// An event property that sets the zone of its times, eg. `@tz Europe/Paris`
+const TZ_PROPERTY: &str = "tz";
+
+#[derive(Debug, Default, Clone)]
+pub struct ResolveOptions {
+    /// When set, an event with only a start time lasts until the next timed event of the same day,
+    /// and the last such event of a day lasts this many minutes.
+    pub time_blocking: Option<u64>,
+    /// Keeps one event per date for flexible occasions, instead of a single recurring event.
+    pub expand_recurrences: bool,
+}
+
 pub enum ResolverAction {
     Set(Ident, IdentData),
     SetTimeZone(TimeZoneChoice),
+    SetTimeBlocking(Option<u64>),
     InsertRecord(ExactRecord),
     InsertTodo(Todo),
     InsertRecords(Vec<ExactRecord>)
 }
 
-pub fn resolve(records: Vec<Record>, base_t: ExactDateTime) -> Vec<ExactRecord> {
+pub fn resolve(records: Vec<Record>, base_t: ExactDateTime, options: &ResolveOptions) -> (Vec<ExactRecord>, Vec<Diagnostic>) {
 
     let mut base = {
         let tyear = base_t.date.year;
@@ -31,6 +50,8 @@ pub fn resolve(records: Vec<Record>, base_t: ExactDateTime) -> Vec<ExactRecord>
                     year: Number(tyear as i64),
                     month: Unsure,
                     day: Unsure,
+                    relative: None,
+                    year_date: None,
                 }),
                 time: None,
                 tz: None,
@@ -43,32 +64,55 @@ pub fn resolve(records: Vec<Record>, base_t: ExactDateTime) -> Vec<ExactRecord>
 
     let mut resolved = vec![];
     let mut baseref = Rc::new(base);
+    let root = Rc::clone(&baseref);
+    // A flexible occasion applies to the events after it, up until the next occasion
+    let mut flex_scope: Option<(FlexOccasion, Span)> = None;
+    let mut blocks = TimeBlocks {
+        cap: options.time_blocking,
+        open: vec![],
+    };
+    // Indices of the events resolved from the same flexible event
+    let mut series: Vec<(RecurrenceRule, Vec<usize>)> = vec![];
     for record in records {
         match record {
             Record::Event(event) => {
-                let event = resolve_event(&event, &baseref);
-                match event {
-                    Ok(event) => resolved.push(ExactRecord::Event(event)),
-                    Err(e) => eprintln!("Error resolving event: {}", e),
+                if let Some((occasion, span)) = &flex_scope {
+                    resolve_flex(occasion, std::slice::from_ref(&event), *span, &baseref, &mut resolved, &mut blocks, &mut series);
+                    continue;
+                }
+                match resolve_event(&event, &baseref) {
+                    Ok(res) => blocks.push(&event, res, &mut resolved),
+                    Err(e) => baseref.report(Diagnostic::from_error("resolve-event", &e)),
                 }
             }
-            Record::Occasion(occasion) => {
-                let fixed_occasion = resolve_occasion(&occasion, &baseref);
+            Record::Occasion(mut occasion, span) => {
+                flex_scope = None;
+                let fixed_occasion = resolve_occasion(&occasion, &baseref).map_err(|e| span.wrap(e));
                 // PERFORMANCE: update base inplace
                 match fixed_occasion {
                     Ok(o) => {
+                        // Relative, ordinal and week dates are pinned down, so that later occasions inherit
+                        // from them. A week without a day keeps scoping the events that follow to the week.
+                        let pinned = match occasion.date {
+                            Some(Date { relative: Some(_), .. }) => true,
+                            Some(Date { year_date: Some(year_date), .. }) => !matches!(year_date, YearDate::Week(_, None)),
+                            _ => false,
+                        };
+                        if pinned {
+                            occasion.date = Some(Date::from_ymd(o.date.year, o.date.month, o.date.day));
+                        }
                         base = Environment::new(o, occasion, Some(Rc::clone(&baseref)));
                         baseref = Rc::new(base);
                     }
-                    Err(e) => eprintln!("Error resolving occasion: {}", e),
+                    Err(e) => baseref.report(Diagnostic::from_error("resolve-occasion", &e)),
                 }
             }
-            Record::Note(note) => {
-                resolved.push(ExactRecord::Note(note.to_string()));
+            Record::Note(note, span) => {
+                resolved.push(ExactRecord::Note(note, Some(span)));
             }
             Record::Command(cmd) => {
-                match cmd.run(baseref.as_ref()) {
-                    Err(e) => {eprintln!("Error when resolving Command: {:?}", e);}
+                match cmd.run(baseref.as_ref()).map_err(|e| cmd.span.wrap(e)) {
+                    Err(e) => baseref.report(Diagnostic::from_error("command", &e)),
                     Ok(Some(cmds)) => {
                         for c in cmds {
                             match c {
@@ -76,6 +120,7 @@ pub fn resolve(records: Vec<Record>, base_t: ExactDateTime) -> Vec<ExactRecord>
                                 ResolverAction::InsertRecord(rec) => {resolved.push(rec);},
                                 ResolverAction::InsertRecords(recs) => {resolved.extend(recs);}
                                 ResolverAction::InsertTodo(t) => {resolved.push(ExactRecord::Todo(t));}
+                                ResolverAction::SetTimeBlocking(cap) => {blocks.cap = cap;}
                                 ResolverAction::SetTimeZone(tz) => {
                                     let nbase = Environment::new(
                                         ExactDateTime {
@@ -92,44 +137,217 @@ pub fn resolve(records: Vec<Record>, base_t: ExactDateTime) -> Vec<ExactRecord>
                     Ok(_) => {}
                 }
             }
-            Record::FlexOccasion(occasion) => {
-                // Filters
-                eprintln!("{:?}", occasion);
-                todo!()
+            Record::FlexOccasion(occasion, span) => {
+                flex_scope = Some((occasion, span));
             }
-            Record::FlexEvents(flex_events) => match &flex_events.occasion {
-                FlexOccasion::Filter(filter) => {
-                    for date in (*baseref).iter() {
-                        let tmp_env = Environment::new(
-                            ExactDateTime {
-                                date: resolve_date(&date, &baseref).unwrap(),
-                                time: ExactTime {
-                                    hour: 0,
-                                    minute: 0,
-                                    second: 0,
-                                },
-                                tz: TimeZoneChoice::Local,
-                            },
-                            DateTime {
-                                date: Some(date),
-                                time: None,
-                                tz: None,
-                            },
-                            Some(Rc::clone(&baseref)),
-                        );
-                        if filter.check(&date, Some(&(*baseref))) {
-                            for event in &flex_events.events {
-                                if let Ok(res) = resolve_event(event, &tmp_env) {
-                                    resolved.push(ExactRecord::Event(res));
-                                }
-                            }
-                        }
-                    }
+            Record::FlexEvents(flex_events) => {
+                resolve_flex(&flex_events.occasion, &flex_events.events, flex_events.span, &baseref, &mut resolved, &mut blocks, &mut series);
+                flex_scope = Some((flex_events.occasion, flex_events.span));
+            }
+        }
+    }
+    blocks.close(&mut resolved);
+    if !options.expand_recurrences {
+        collapse_series(&mut resolved, series);
+    }
+    let diagnostics = root.diagnostics.take();
+    (resolved, diagnostics)
+}
+
+/// Events that only have a start time, resolved while time-blocking is on.
+struct TimeBlocks {
+    cap: Option<u64>,
+    // Index into the resolved records, along with the cap in effect for the event
+    open: Vec<(usize, u64)>,
+}
+
+impl TimeBlocks {
+    fn push(&mut self, event: &Event, res: ExactEvent, resolved: &mut Vec<ExactRecord>) {
+        if let (Some(cap), Range::Duration(Duration { duration: Unsure, .. })) = (self.cap, &event.range) {
+            self.open.push((resolved.len(), cap));
+        }
+        resolved.push(ExactRecord::Event(res));
+    }
+
+    /// Ends every open event at the start of the next timed event on the same day in its zone,
+    /// or after the cap if it is the last one of the day.
+    fn close(self, resolved: &mut [ExactRecord]) {
+        let starts: Vec<(chrono::DateTime<chrono::Utc>, ExactDateTime)> = resolved
+            .iter()
+            .filter_map(|r| match r {
+                ExactRecord::Event(ExactEvent { range: ExactRange::TimeRange(tr), .. }) => {
+                    Some((tr.start.to_chrono().ok()?, tr.start.clone()))
                 }
+                _ => None,
+            })
+            .collect();
+        for (idx, cap) in self.open {
+            let range = match &mut resolved[idx] {
+                ExactRecord::Event(ExactEvent { range: ExactRange::TimeRange(tr), .. }) => tr,
+                _ => continue,
+            };
+            let start = match range.start.to_chrono() {
+                Ok(start) => start,
+                Err(_) => continue,
+            };
+            let next = starts
+                .iter()
+                .filter(|(t, _)| *t > start && ExactDateTime::from_chrono_in(*t, range.start.tz).date == range.start.date)
+                .min_by_key(|(t, _)| *t);
+            range.end = match next {
+                Some((_, dt)) => dt.clone(),
+                None => match start.checked_add_signed(chrono::Duration::minutes(cap as i64)) {
+                    Some(end) => ExactDateTime::from_chrono_in(end, range.start.tz),
+                    None => continue,
+                },
+            };
+        }
+    }
+}
+
+/// Resolves `events` once for every date in the scope of `base` that fits the flexible occasion.
+fn resolve_flex(
+    occasion: &FlexOccasion,
+    events: &[Event],
+    span: Span,
+    base: &Rc<Environment>,
+    resolved: &mut Vec<ExactRecord>,
+    blocks: &mut TimeBlocks,
+    series: &mut Vec<(RecurrenceRule, Vec<usize>)>,
+) {
+    let mut groups = vec![vec![]; events.len()];
+    let time = match &occasion.time {
+        Some(time) => match resolve_time(time, base) {
+            Ok(t) => t,
+            Err(e) => {
+                base.report(Diagnostic::from_error("resolve-occasion", &span.wrap(e)));
+                return;
+            }
+        },
+        None => ExactTime::from_hms(0, 0, 0),
+    };
+    let dates: Vec<Date> = match &occasion.window {
+        Some(window) => match resolve_window(window, base) {
+            Ok(dates) => dates,
+            Err(e) => {
+                base.report(Diagnostic::from_error("resolve-occasion", &span.wrap(e)));
+                return;
+            }
+        },
+        None => base.iter().collect(),
+    };
+    for date in occasion.filter.filter(dates, Some(base)) {
+        let exact_date = match resolve_date(&date, base) {
+            Ok(d) => d,
+            Err(e) => {
+                base.report(Diagnostic::from_error("resolve-occasion", &span.wrap(e)));
+                continue;
+            }
+        };
+        let tmp_env = Environment::new(
+            ExactDateTime {
+                date: exact_date,
+                time,
+                tz: base.date_time.tz,
             },
+            DateTime {
+                date: Some(date),
+                time: occasion.time,
+                tz: None,
+            },
+            Some(Rc::clone(base)),
+        );
+        for (event, group) in events.iter().zip(groups.iter_mut()) {
+            match resolve_event(event, &tmp_env) {
+                Ok(res) => {
+                    group.push(resolved.len());
+                    blocks.push(event, res, resolved);
+                }
+                Err(e) => base.report(Diagnostic::from_error("resolve-event", &e)),
+            }
+        }
+    }
+    // Only filters that a recurrence rule can express are written as one
+    if let Some(rule) = occasion.filter.pattern(Some(base)).and_then(|p| p.rule()) {
+        series.extend(groups.into_iter().map(|group| (rule.clone(), group)));
+    }
+}
+
+/// Every date in the window of a flexible occasion.
+fn resolve_window(window: &Window, base: &Environment) -> Result<Vec<Date>> {
+    let range = match window {
+        Window::Between(start, end) => ExactDateRange {
+            start: resolve_date(start, base)?,
+            end: resolve_date(end, base)?,
+        },
+        Window::Named(ident) => match base.get(&ident.name) {
+            Some(IdentData::Value(Value::DateFilter(filter))) => filter
+                .window(Some(base))
+                .ok_or_else(|| anyhow!("{} does not cover a limited range of dates", ident.name))?,
+            _ => return Err(anyhow!("{} is not a date filter", ident.name)),
+        },
+    };
+    if range.end < range.start {
+        return Err(anyhow!("The window ends before it starts"));
+    }
+    let (start, end) = (range.start.to_chrono()?, range.end.to_chrono()?);
+    Ok(start
+        .iter_days()
+        .take_while(|d| *d <= end)
+        .map(Date::from_naive)
+        .collect())
+}
+
+/// Replaces every series of events that only differ in their dates with a single recurring event,
+/// as long as the rule of their filter fits the dates.
+fn collapse_series(resolved: &mut Vec<ExactRecord>, series: Vec<(RecurrenceRule, Vec<usize>)>) {
+    let mut removed = vec![false; resolved.len()];
+    for (rule, indices) in series {
+        let events: Vec<&ExactEvent> = indices
+            .iter()
+            .filter_map(|&i| match &resolved[i] {
+                ExactRecord::Event(e) => Some(e),
+                _ => None,
+            })
+            .collect();
+        let first = match events.first() {
+            Some(first) => first,
+            None => continue,
+        };
+        let shape = event_shape(first);
+        let alike = shape.is_some()
+            && events
+                .iter()
+                .all(|e| e.name == first.name && e.notes == first.notes && event_shape(e) == shape);
+        if !alike {
+            continue;
+        }
+        let dates: Vec<ExactDate> = events.iter().map(|e| e.range.start_date()).collect();
+        if let Some(recurrence) = Recurrence::new(rule, &dates) {
+            if let ExactRecord::Event(e) = &mut resolved[indices[0]] {
+                e.recurrence = Some(Box::new(recurrence));
+            }
+            for &i in &indices[1..] {
+                removed[i] = true;
+            }
+        }
+    }
+    let mut removed = removed.into_iter();
+    resolved.retain(|_| !removed.next().unwrap_or(false));
+}
+
+/// What an event looks like regardless of its date: the start time and zone, and how long it lasts.
+fn event_shape(event: &ExactEvent) -> Option<(Option<(ExactTime, TimeZoneChoice)>, i64)> {
+    match &event.range {
+        ExactRange::TimeRange(tr) => {
+            let length = (tr.end.to_chrono().ok()? - tr.start.to_chrono().ok()?).num_seconds();
+            Some((Some((tr.start.time, tr.start.tz)), length))
+        }
+        ExactRange::AllDay(range) => {
+            let length = (range.end.to_chrono().ok()? - range.start.to_chrono().ok()?).num_days();
+            Some((None, length))
         }
     }
-    resolved
 }
 
 // Should it really be named occasion... perhaps rename it to resolve_datetime?
@@ -145,7 +363,7 @@ pub fn resolve_occasion(occasion: &DateTime, base: &Environment) -> Result<Exact
         } else {
             base.date_time.time
         },
-        tz: base.date_time.tz,
+        tz: occasion.tz.unwrap_or(base.date_time.tz),
     })
 }
 
@@ -206,39 +424,132 @@ pub fn resolve_time(time: &Time, base: &Environment) -> Result<ExactTime> {
 }
 
 pub fn resolve_date(date: &Date, base: &Environment) -> Result<ExactDate> {
-    let base_date = base.date_time.date;
-    let res = ExactDate {
+    match (date.relative, date.year_date) {
+        (Some(relative), _) => resolve_relative_date(relative, base.date_time.date),
+        (None, Some(year_date)) => resolve_year_date(date.year, year_date, base.date_time.date),
+        (None, None) => Ok(inherit_date(date, base.date_time.date)),
+    }
+}
+
+/// Resolves a week or ordinal date, the first day of the week if no weekday is given.
+fn resolve_year_date(year: NumVal, year_date: YearDate, base: ExactDate) -> Result<ExactDate> {
+    let year = match year {
+        Number(n) => i32::try_from(n)?,
+        Unsure => base.year,
+    };
+    let res = match year_date {
+        YearDate::Week(week, weekday) => NaiveDate::from_isoywd_opt(year, week, chrono::Weekday::Mon)
+            .and_then(|monday| monday.checked_add_days(chrono::Days::new(weekday.unwrap_or(1).saturating_sub(1) as u64))),
+        YearDate::Ordinal(day) => NaiveDate::from_yo_opt(year, day),
+    };
+    match res {
+        Some(d) => Ok(ExactDate::from_naive(d)),
+        None => Err(anyhow!("Invalid date: {:?} of {}", year_date, year)),
+    }
+}
+
+fn resolve_relative_date(relative: RelativeDate, base: ExactDate) -> Result<ExactDate> {
+    let base_date = base.to_chrono()?;
+    let res = match relative {
+        RelativeDate::Days(n) if n >= 0 => base_date.checked_add_days(chrono::Days::new(n as u64)),
+        RelativeDate::Days(n) => base_date.checked_sub_days(chrono::Days::new(n.unsigned_abs())),
+        RelativeDate::Next(weekday) => base_date
+            .iter_days()
+            .skip(1)
+            .take(7)
+            .find(|d| d.weekday() == weekday),
+    };
+    match res {
+        Some(d) => Ok(ExactDate::from_naive(d)),
+        None => Err(anyhow!("Date out of range: {:?} from {}", relative, base)),
+    }
+}
+
+/// The end of a range inherits from its start, while relative dates are still taken from the environment.
+fn resolve_end_date(date: &Date, start: ExactDate, base: &Environment) -> Result<ExactDate> {
+    match (date.relative, date.year_date) {
+        (Some(_), _) => resolve_date(date, base),
+        (None, Some(year_date)) => resolve_year_date(date.year, year_date, start),
+        (None, None) => Ok(inherit_date(date, start)),
+    }
+}
+
+/// Fills in the unspecified fields of `date` from `base`.
+fn inherit_date(date: &Date, base: ExactDate) -> ExactDate {
+    ExactDate {
         year: match date.year {
             Number(n) => n as i32,
-            _ => base_date.year,
+            _ => base.year,
         },
         month: match date.month {
             Number(n) => n as u32,
-            _ => base_date.month,
+            _ => base.month,
         },
         day: match date.day {
             Number(n) => n as u32,
-            _ => base_date.day,
+            _ => base.day,
         },
-    };
-    Ok(res)
+    }
 }
 
 pub fn resolve_event(event: &Event, base: &Environment) -> Result<ExactEvent> {
+    let range = match event_timezone(event)? {
+        Some(tz) => with_timezone(&event.range, tz),
+        None => event.range.clone(),
+    };
+    let range = resolve_range(&range, base).map_err(|e| event.span.wrap(e))?;
+    // Catch dates and times that cannot be exported here, while the span is still at hand
+    match &range {
+        ExactRange::TimeRange(tr) => {
+            tr.start.to_chrono().map_err(|e| event.span.wrap(e))?;
+            tr.end.to_chrono().map_err(|e| event.span.wrap(e))?;
+        }
+        ExactRange::AllDay(range) => {
+            range.start.to_chrono().map_err(|e| event.span.wrap(e))?;
+            range.end.to_chrono().map_err(|e| event.span.wrap(e))?;
+        }
+    }
     Ok(ExactEvent {
-        range: resolve_range(&event.range, base)?,
+        range,
         name: event.name.clone(),
         notes: match &event.notes{
-            Some(n) => Some(resolve_notes(n, base)?),
+            Some(n) => Some(resolve_notes(n, base).map_err(|e| event.span.wrap(e))?),
             None => None
-        }
+        },
+        span: Some(event.span),
+        recurrence: None,
     })
 }
 
+/// The zone set for an event with a `@tz` property.
+fn event_timezone(event: &Event) -> Result<Option<TimeZoneChoice>> {
+    let property = match event.notes.iter().flat_map(|n| &n.properties).find(|p| p.name == TZ_PROPERTY) {
+        Some(property) => property,
+        None => return Ok(None),
+    };
+    let tz = match &property.data {
+        Value::Ident(ident) => parse_timezone(&ident.name),
+        Value::String(s) => parse_timezone(s),
+        _ => Err(anyhow!("@{} takes the name of a time zone", TZ_PROPERTY)),
+    };
+    tz.map(Some).map_err(|e| property.span.wrap(e))
+}
+
+/// Gives the times of a range that have no zone of their own the zone `tz`.
+fn with_timezone(range: &Range, tz: TimeZoneChoice) -> Range {
+    let zoned = |dt: &DateTime| DateTime { tz: dt.tz.or(Some(tz)), ..dt.clone() };
+    match range {
+        Range::Time(tr) => Range::Time(TimeRange { start: zoned(&tr.start), end: zoned(&tr.end) }),
+        Range::Duration(d) => Range::Duration(Duration { start: zoned(&d.start), duration: d.duration }),
+        Range::AllDay(date) => Range::AllDay(*date),
+    }
+}
+
 pub fn resolve_notes(notes: &Notes, base: &Environment) -> Result<ExactNotes> {
     let mut properties:Vec<ExactProperty> = vec![];
-    for prop in &notes.properties {
-        properties.push(resolve_property(prop, base)?);
+    // The zone is taken by the times of the event, rather than written out as a property
+    for prop in notes.properties.iter().filter(|p| p.name != TZ_PROPERTY) {
+        properties.push(resolve_property(prop, base).map_err(|e| prop.span.wrap(e))?);
     }
     Ok(ExactNotes {
         description: notes.description.clone(),
@@ -268,27 +579,74 @@ pub fn resolve_range(range: &Range, base: &Environment) -> Result<ExactRange> {
     Ok(match range {
         Range::AllDay(date) => {
             let date = resolve_date(date, base)?;
-            ExactRange::AllDay(date)
+            ExactRange::AllDay(ExactDateRange::single(date))
+        }
+        // A range between two dates spans whole days
+        Range::Time(TimeRange {
+            start: DateTime { date: Some(start), time: None, .. },
+            end: DateTime { date: Some(end), time: None, .. },
+        }) => {
+            let start = resolve_date(start, base)?;
+            let end = resolve_end_date(end, start, base)?;
+            if end < start {
+                return Err(anyhow!("Range ends before it starts: {} ~ {}", start, end));
+            }
+            ExactRange::AllDay(ExactDateRange { start, end })
         }
         Range::Time(time_range) => {
             let start = resolve_occasion(&time_range.start, base)?;
-            let end = resolve_occasion(&time_range.end, base)?;
+            let end = resolve_range_end(&time_range.end, &start, base)?;
             ExactRange::TimeRange(ExactTimeRange { start, end })
         }
         Range::Duration(duration) => {
-            // ExactRange::Duration(resolve_duration(duration, base)?)
-            let start = resolve_occasion(&duration.start, base)?;
-            let shift = chrono::Duration::minutes(match duration.duration {
-                Number(n) => n,
-                _ => 30,
-            });
-            let end_ch = start.to_chrono()? + shift;
-            let end = ExactDateTime::from_chrono(end_ch);
-            ExactRange::TimeRange(ExactTimeRange { start, end })
+            let duration = resolve_duration(duration, base)?;
+            let end_ch = duration
+                .start
+                .to_chrono()?
+                .checked_add_signed(chrono::Duration::minutes(duration.duration as i64))
+                .ok_or_else(|| anyhow!("Event ends too far in the future"))?;
+            let end = ExactDateTime::from_chrono_in(end_ch, duration.start.tz);
+            ExactRange::TimeRange(ExactTimeRange {
+                start: duration.start,
+                end,
+            })
         }
     })
 }
 
+/// Resolves the end of a range, which takes the date of its start where one is not given.
+/// `24:00` is the end of that day, and an end time before the start rolls over to the next day.
+fn resolve_range_end(end: &DateTime, start: &ExactDateTime, base: &Environment) -> Result<ExactDateTime> {
+    let date = match &end.date {
+        Some(date) => resolve_end_date(date, start.date, base)?,
+        None => start.date,
+    };
+    let end_of_day = matches!(
+        end.time,
+        Some(Time { hour: Number(24), minute: Number(0) | Unsure, second: Number(0) | Unsure, tod: None })
+    );
+    let mut res = ExactDateTime {
+        date,
+        time: match &end.time {
+            Some(_) if end_of_day => ExactTime::from_hms(0, 0, 0),
+            Some(time) => resolve_time(time, base)?,
+            None => base.date_time.time,
+        },
+        // The end is in the zone of the start unless it has its own, eg. `9am America/Los_Angeles~6pm America/New_York`
+        tz: end.tz.unwrap_or(start.tz),
+    };
+    if end_of_day {
+        res.date = res.date.succ()?;
+    }
+    if res.to_chrono()? < start.to_chrono()? {
+        if end.date.is_some() || end_of_day {
+            return Err(anyhow!("Range ends before it starts: {} ~ {}", start, res));
+        }
+        res.date = res.date.succ()?;
+    }
+    Ok(res)
+}
+
 pub fn resolve_duration(duration: &Duration, base: &Environment) -> Result<ExactDuration> {
     let start = resolve_occasion(&duration.start, base)?;
     let dur = match duration.duration {
@@ -296,9 +654,13 @@ pub fn resolve_duration(duration: &Duration, base: &Environment) -> Result<Exact
             if n < 0 {
                 return Err(anyhow!("Duration cannot be negative"));
             }
+            // chrono durations are bounded by i64 milliseconds
+            if n > i64::MAX / 60_000 {
+                return Err(anyhow!("Duration is too long"));
+            }
             n as u64
         }
-        _ => 30,
+        _ => DEFAULT_DURATION,
     };
     Ok(ExactDuration {
         start,
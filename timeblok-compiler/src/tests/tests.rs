@@ -1,10 +1,12 @@
-use crate::{compile_deterministic, ir::ExactDateTime};
+use crate::{compile_deterministic, compile_deterministic_with_options, diagnostic::has_errors, ir::ExactDateTime};
+use crate::{records_to_resolved_with_options, tb_to_records, ResolveOptions};
+use crate::ir::{ExactEvent, ExactRange, ExactRecord, TimeZoneChoice};
 use insta::{assert_snapshot, glob};
 use std::fs;
 
 macro_rules! assert_compile_snapshot {
     ($input:expr, $base_time:expr) => {
-        assert_snapshot!(compile_deterministic($input, $base_time.clone()).unwrap());
+        assert_snapshot!(compile_deterministic($input, $base_time.clone()).unwrap().0);
     };
 
     ($input:expr) => {
@@ -29,4 +31,107 @@ fn run_tests(){
         let input = fs::read_to_string(path).unwrap();
         assert_compile_snapshot!(&input);
     });
-}
\ No newline at end of file
+}
+#[test]
+fn diagnostics(){
+    let (_, diagnostics) = compile_deterministic(
+        "2023-4-4\n25:00 too late\n/nosuch arg\n",
+        ExactDateTime::from_ymd_hms(2023, 1, 1, 0, 0, 0),
+    ).unwrap();
+    let found: Vec<(&str, usize)> = diagnostics
+        .iter()
+        .map(|d| (d.code, d.span.unwrap().line))
+        .collect();
+    assert_eq!(found, vec![("resolve-event", 2), ("command", 3)]);
+    assert!(has_errors(&diagnostics));
+}
+
+#[test]
+fn window_errors(){
+    let sources = [
+        "{mon} in nosuch\n10am x\n",
+        "/set weekly {mon}\n{tue} in weekly\n10am x\n",
+        "{mon} from 2023-2-1 until 2023-1-1\n10am x\n",
+    ];
+    for source in sources {
+        let (_, diagnostics) = compile_deterministic(
+            source,
+            ExactDateTime::from_ymd_hms(2023, 1, 1, 0, 0, 0),
+        ).unwrap();
+        let codes: Vec<&str> = diagnostics.iter().map(|d| d.code).collect();
+        assert_eq!(codes, vec!["resolve-occasion"], "{}", source);
+    }
+}
+
+#[test]
+fn no_panics(){
+    let sources = [
+        "2023-2-30\n{mon}\n10am x\n",
+        "-2-\n{mon or wed} 10am\n: x\n",
+        "{sat}\n10am x\n",
+        "{2023-1-1~2023-1-3}\n10am x\n",
+        "2023-1-1\n{nosuch}\n10am x\n",
+    ];
+    for source in sources {
+        assert!(compile_deterministic(source, ExactDateTime::from_ymd_hms(2023, 1, 1, 0, 0, 0)).is_ok());
+    }
+}
+
+// A base time in UTC, so that the results do not depend on the local time zone
+fn utc_base() -> ExactDateTime {
+    ExactDateTime { tz: TimeZoneChoice::Utc, ..ExactDateTime::from_ymd_hms(2023, 1, 1, 0, 0, 0) }
+}
+
+#[test]
+fn time_blocking(){
+    let (ics, diagnostics) = compile_deterministic_with_options(
+        include_str!("timeblocking.tb"),
+        utc_base(),
+        &ResolveOptions::default(),
+    ).unwrap();
+    assert!(diagnostics.is_empty());
+    assert_snapshot!(ics);
+}
+
+#[test]
+fn time_blocking_option(){
+    let (records, _) = tb_to_records("2023-4-5\n9am a\n10am b\n11am (15m) c\n").unwrap();
+    let options = ResolveOptions { time_blocking: Some(45), ..Default::default() };
+    let (resolved, _) = records_to_resolved_with_options(records, utc_base(), &options).unwrap();
+    let ends: Vec<String> = resolved
+        .iter()
+        .map(|r| match r {
+            ExactRecord::Event(ExactEvent { range: ExactRange::TimeRange(tr), .. }) => tr.end.to_chrono().unwrap().to_rfc3339(),
+            r => panic!("expected timed event, got {:?}", r),
+        })
+        .collect();
+    let expected: Vec<String> = [(10, 0), (11, 0), (11, 15)]
+        .iter()
+        .map(|&(h, m)| format!("2023-04-05T{:02}:{:02}:00+00:00", h, m))
+        .collect();
+    assert_eq!(ends, expected);
+
+    // 11pm in New York is already the next day in UTC
+    let (records, _) = tb_to_records("2023-4-5\n9am a\n11pm America/New_York b\n").unwrap();
+    let (resolved, _) = records_to_resolved_with_options(records, utc_base(), &options).unwrap();
+    match &resolved[0] {
+        ExactRecord::Event(ExactEvent { range: ExactRange::TimeRange(tr), .. }) => {
+            assert_eq!(tr.end.to_chrono().unwrap().to_rfc3339(), "2023-04-05T09:45:00+00:00");
+        }
+        r => panic!("expected timed event, got {:?}", r),
+    }
+}
+
+#[test]
+fn negative_range(){
+    let (_, diagnostics) = compile_deterministic(
+        "2023-4-5\n2023-4-6 10am~2023-4-5 9am backwards\n24:00~25:00 past midnight\n",
+        ExactDateTime::from_ymd_hms(2023, 1, 1, 0, 0, 0),
+    ).unwrap();
+    let found: Vec<(&str, usize)> = diagnostics
+        .iter()
+        .map(|d| (d.code, d.span.unwrap().line))
+        .collect();
+    assert_eq!(found, vec![("resolve-event", 2), ("resolve-event", 3)]);
+    assert!(diagnostics[0].message.contains("ends before it starts"));
+}
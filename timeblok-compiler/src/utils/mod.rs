@@ -1,10 +1,10 @@
 use std::fs;
-use std::fs::{create_dir_all, File};
+use std::fs::File;
 use std::path::PathBuf;
 #[cfg(not(target_family = "wasm"))]
 use reqwest::Url;
 use anyhow::{anyhow, Result};
-use directories::{ProjectDirs, BaseDirs, UserDirs};
+use directories::{ProjectDirs, BaseDirs};
 
 use crate::ir::{ExactDateTime, ExactDate, ExactTime, TimeZoneChoice};
 use std::time::SystemTime;
@@ -57,6 +57,7 @@ pub async fn download_file_wasm(filename: &str, dest: PathBuf, display_name: Opt
     Ok(())
 }
 
+#[allow(dead_code)]
 pub enum Dirs{
     Project,
     Data,
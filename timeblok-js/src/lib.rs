@@ -1,4 +1,7 @@
+use anyhow::Result;
+use timeblok::diagnostic::Diagnostic;
 use timeblok::ir::{ExactDateTime, ExactRecord, ExactRange, TimeZoneChoice};
+use timeblok::IcsTimes;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_test::*;
 mod utils;
@@ -19,29 +22,106 @@ macro_rules! log_debug {
 
 #[wasm_bindgen]
 pub fn compile(source: &str, base_time: i64) -> Option<String> {
-    match timeblok::compile(source, ExactDateTime::from_timestamp(base_time)?) {
-        Ok(ics) => Some(ics),
-        Err(_) => None,
-    }
+    timeblok::compile(source, ExactDateTime::from_timestamp(base_time)?).ok().map(|(ics, _)| ics)
 }
 
 #[wasm_bindgen]
 pub fn compile_with_basedate(source: &str, year: i32, month: u32, day: u32) -> Option<String> {
-    match timeblok::compile(source, ExactDateTime::from_ymd_hms(year, month, day,0,0,0)) {
-        Ok(ics) => Some(ics),
-        Err(_) => None,
+    timeblok::compile(source, ExactDateTime::from_ymd_hms(year, month, day,0,0,0)).ok().map(|(ics, _)| ics)
+}
+
+/// The output of `compile_with_diagnostics`, `compile_jcal` or `compile_xcal`, along with the problems found while compiling
+#[wasm_bindgen]
+pub struct Compiled {
+    output: Option<String>,
+    diagnostics: String,
+}
+
+impl Compiled {
+    fn new(source: &str, result: Result<(String, Vec<Diagnostic>)>) -> Self {
+        match result {
+            Ok((output, diagnostics)) => Compiled {
+                output: Some(output),
+                diagnostics: diagnostics
+                    .iter()
+                    .map(|d| d.render(source))
+                    .collect::<Vec<String>>()
+                    .join("\n\n"),
+            },
+            Err(e) => Compiled {
+                output: None,
+                diagnostics: format!("error: {}", e),
+            },
+        }
     }
 }
 
+#[wasm_bindgen]
+impl Compiled {
+    /// The compiled calendar, in whichever format was asked for
+    #[wasm_bindgen(getter)]
+    pub fn output(&self) -> Option<String> {
+        self.output.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn ics(&self) -> Option<String> {
+        self.output.clone()
+    }
+
+    /// The problems, rendered with source snippets
+    #[wasm_bindgen(getter)]
+    pub fn diagnostics(&self) -> String {
+        self.diagnostics.clone()
+    }
+}
+
+#[wasm_bindgen]
+pub fn compile_with_diagnostics(source: &str, year: i32, month: u32, day: u32) -> Compiled {
+    Compiled::new(source, timeblok::compile(source, ExactDateTime::from_ymd_hms(year, month, day,0,0,0)))
+}
+
+type Converter = fn(Vec<ExactRecord>, IcsTimes) -> Result<(String, Vec<Diagnostic>)>;
+
+fn compile_to(
+    source: &str,
+    base_time: ExactDateTime,
+    convert: Converter,
+) -> Result<(String, Vec<Diagnostic>)> {
+    let (records, mut diagnostics) = timeblok::tb_to_records(source)?;
+    let (resolved, resolve_diagnostics) = timeblok::records_to_resolved(records, base_time)?;
+    diagnostics.extend(resolve_diagnostics);
+    let (output, convert_diagnostics) = convert(resolved, IcsTimes::default())?;
+    diagnostics.extend(convert_diagnostics);
+    Ok((output, diagnostics))
+}
+
+/// Compiles `source` to jCal (RFC 7265); `output` can be read with `JSON.parse`
+#[wasm_bindgen]
+pub fn compile_jcal(source: &str, year: i32, month: u32, day: u32) -> Compiled {
+    let base_time = ExactDateTime::from_ymd_hms(year, month, day,0,0,0);
+    Compiled::new(source, compile_to(source, base_time, timeblok::resolved_to_jcal))
+}
+
+/// Compiles `source` to xCal (RFC 6321)
+#[wasm_bindgen]
+pub fn compile_xcal(source: &str, year: i32, month: u32, day: u32) -> Compiled {
+    let base_time = ExactDateTime::from_ymd_hms(year, month, day,0,0,0);
+    Compiled::new(source, compile_to(source, base_time, timeblok::resolved_to_xcal))
+}
+
 #[wasm_bindgen]
 pub fn compile_verbose(source: &str, year: i32, month: u32, day: u32) -> Option<String> {
     log!("VERSION:");
     log!(env!("CARGO_PKG_VERSION"));
-    let records = timeblok::tb_to_records(&source.to_string()).ok()?;
+    let (records, diagnostics) = timeblok::tb_to_records(source).ok()?;
     log_debug!(records);
-    let resolved = timeblok::records_to_resolved(records, ExactDateTime::from_ymd_hms(year, month, day,0,0,0)).ok()?;
+    log_debug!(diagnostics);
+    let (resolved, diagnostics) = timeblok::records_to_resolved(records, ExactDateTime::from_ymd_hms(year, month, day,0,0,0)).ok()?;
     log_debug!(resolved);
-    let ics = timeblok::resolved_to_ical(resolved).ok()?;
+    log_debug!(diagnostics);
+    let (ics, diagnostics) = timeblok::resolved_to_ical(resolved).ok()?;
+    log_debug!(diagnostics);
     Some(ics)
 }
 
@@ -57,21 +137,31 @@ pub fn test_compile() {
     let res = compile_with_basedate("/t do stuff\n2023-4-\n{mon}\n10am do stuff", 2023, 4, 7);
     assert!(res.is_some());
     if let Some(ref icsdat) = res {
+        // The todo, and the Mondays as one repeating event
         let resolved = ics_to_records(&import_ics(icsdat).unwrap());
-        assert!(resolved.len()==5);
+        assert!(resolved.len()==2);
+        let occurrences = resolved.iter().map(|record| match record {
+            ExactRecord::Event(ev) => ev.occurrences().len(),
+            _ => 0,
+        });
+        assert!(occurrences.sum::<usize>()==4);
     }
+    let jcal = compile_jcal("/t do stuff\n2023-4-\n{mon}\n10am do stuff", 2023, 4, 7).output();
+    assert!(jcal.is_some_and(|jcal| jcal.contains("\"vevent\"") && jcal.contains("\"vtodo\"")));
+    let xcal = compile_xcal("/t do stuff\n2023-4-\n{mon}\n10am do stuff", 2023, 4, 7).output();
+    assert!(xcal.is_some_and(|xcal| xcal.contains("<vevent>") && xcal.contains("<rrule><recur>")));
     let res = compile_with_basedate("/set d {mon or tue}\n2023-4-\n{d}\n10am do stuff", 2023, 4, 7);
     assert!(res.is_some());
     let res = compile_with_basedate("/tz pdt\n2023-5-3\n10am do stuff", 2023, 4, 8);
     if let Some(ref icsdat) = res {
         let resolved = ics_to_records(&import_ics(icsdat).unwrap());
         assert!(resolved.len()==1);
-        let chr = resolved.get(0).unwrap();
+        let chr = resolved.first().unwrap();
         if let ExactRecord::Event(ev) = chr{
             if let ExactRange::TimeRange(tr) = &ev.range{
                 let ch = &tr.start;
-                assert!(ch.tz==TimeZoneChoice::Utc);
-                assert!(ch.time.hour==17);
+                assert!(ch.tz==TimeZoneChoice::Named(chrono_tz::America::Los_Angeles));
+                assert!(ch.time.hour==10);
             }
         }
     }
@@ -4,14 +4,22 @@ use ::timeblok::ir::{ExactDateTime};
 
 #[pyfunction]
 fn compile_with_basedate(source: &str, year: i32, month: u32, day: u32) -> Option<String> {
+    compile(source, ExactDateTime::from_ymd_hms(year, month, day,0,0,0)).ok().map(|(ics, _)| ics)
+}
+
+/// Returns the ics output along with the problems found while compiling `source`,
+/// rendered with source snippets
+#[pyfunction]
+fn compile_with_diagnostics(source: &str, year: i32, month: u32, day: u32) -> (Option<String>, Vec<String>) {
     match compile(source, ExactDateTime::from_ymd_hms(year, month, day,0,0,0)) {
-        Ok(ics) => Some(ics),
-        Err(_) => None,
+        Ok((ics, diagnostics)) => (Some(ics), diagnostics.iter().map(|d| d.render(source)).collect()),
+        Err(e) => (None, vec![format!("error: {}", e)]),
     }
 }
 
 #[pymodule]
 fn timeblok_py(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(compile_with_basedate, m)?)?;
+    m.add_function(wrap_pyfunction!(compile_with_diagnostics, m)?)?;
     Ok(())
 }
\ No newline at end of file